@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::task::JoinSet;
+
+use crate::random::shuffle;
+use crate::trackers::{DiscoverPeersRequest, DiscoverPeersResponse, Peer};
+
+/// The trackers of a torrent grouped in tiers (BEP 12). Trackers within a
+/// tier are tried in a random order and the first one to respond is moved
+/// to the front of its tier, so it is tried first on the next announce.
+pub struct AnnounceList {
+    tiers: Vec<Vec<String>>,
+
+    /// The `tracker id` each tracker last sent, echoed on its next announce.
+    tracker_ids: HashMap<String, String>,
+}
+
+/// The outcome of announcing to all the tiers.
+pub struct Announced {
+    pub peers: Vec<Peer>,
+
+    /// When to announce again: the shortest `interval` among the trackers
+    /// which responded, but never below their `min interval`.
+    pub interval: Option<Duration>,
+}
+
+impl AnnounceList {
+    pub fn new(tiers: Vec<Vec<String>>) -> AnnounceList {
+        let mut tiers: Vec<Vec<String>> = tiers.into_iter().filter(|t| !t.is_empty()).collect();
+        tiers.iter_mut().for_each(|tier| shuffle(tier));
+
+        AnnounceList {
+            tiers,
+            tracker_ids: HashMap::new(),
+        }
+    }
+
+    /// Announces to one tracker of every tier, falling back to the next
+    /// tracker of a tier when one fails, and merges the peers returned by
+    /// all of them. The tiers are announced to at the same time, an HTTP
+    /// tracker gets [`TRACKER_TIMEOUT`](crate::trackers::TRACKER_TIMEOUT) to
+    /// respond and a UDP tracker until its last retransmission times out.
+    /// The announce URL of `request` is replaced by the URL of each tracker
+    /// tried.
+    pub async fn announce(&mut self, request: &DiscoverPeersRequest) -> anyhow::Result<Announced> {
+        if self.tiers.is_empty() {
+            anyhow::bail!("There are no trackers to announce to");
+        }
+
+        let mut announces = JoinSet::new();
+        for (tier_index, tier) in self.tiers.iter().enumerate() {
+            let requests: Vec<DiscoverPeersRequest> = tier
+                .iter()
+                .map(|tracker| DiscoverPeersRequest {
+                    announce_url: tracker.clone(),
+                    tracker_id: self.tracker_ids.get(tracker).cloned(),
+                    ..request.clone()
+                })
+                .collect();
+
+            announces.spawn(async move { (tier_index, announce_to_tier(requests).await) });
+        }
+
+        let mut responses: Vec<Option<(usize, DiscoverPeersResponse)>> =
+            vec![None; self.tiers.len()];
+        while let Some(announced) = announces.join_next().await {
+            let (tier_index, response) = announced?;
+            responses[tier_index] = response;
+        }
+
+        let mut peers: Vec<Peer> = vec![];
+        let mut interval: Option<usize> = None;
+        let mut min_interval: Option<usize> = None;
+        let mut announced = false;
+
+        for (tier, response) in self.tiers.iter_mut().zip(responses) {
+            let Some((i, response)) = response else {
+                continue;
+            };
+
+            if let Some(tracker_id) = &response.tracker_id {
+                self.tracker_ids.insert(tier[i].clone(), tracker_id.clone());
+            }
+
+            if let Some(seconds) = response.interval {
+                interval = Some(interval.map_or(seconds, |i| i.min(seconds)));
+            }
+            if let Some(seconds) = response.min_interval {
+                min_interval = Some(min_interval.map_or(seconds, |i| i.max(seconds)));
+            }
+
+            for peer in response.parse_peers() {
+                if !peers.iter().any(|p| p.has_address_of(&peer)) {
+                    peers.push(peer);
+                }
+            }
+
+            let tracker = tier.remove(i);
+            tier.insert(0, tracker);
+            announced = true;
+        }
+
+        if !announced {
+            anyhow::bail!("Could not get peers from any tracker");
+        }
+
+        Ok(Announced {
+            peers,
+            interval: interval.map(|interval| {
+                Duration::from_secs(interval.max(min_interval.unwrap_or(0)) as u64)
+            }),
+        })
+    }
+}
+
+/// Tries the trackers of a tier in order, returning the position of the
+/// first one to respond and its response.
+async fn announce_to_tier(
+    requests: Vec<DiscoverPeersRequest>,
+) -> Option<(usize, DiscoverPeersResponse)> {
+    for (i, request) in requests.iter().enumerate() {
+        match request.send().await {
+            Ok(response) => {
+                if let Some(warning) = &response.warning_message {
+                    eprintln!("Warning from {}: {}", request.announce_url, warning);
+                }
+                return Some((i, response));
+            }
+            Err(err) => eprintln!("Could not get peers from {}: {}", request.announce_url, err),
+        }
+    }
+
+    None
+}
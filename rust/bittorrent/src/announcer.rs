@@ -0,0 +1,118 @@
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+
+use crate::announce_list::AnnounceList;
+use crate::random::random_u32;
+use crate::seed::LISTEN_PORT;
+use crate::trackers::{AnnounceEvent, DiscoverPeersRequest, Peer};
+
+/// How often to announce when no tracker sent an interval.
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// The shortest interval between announces, whatever the trackers sent.
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of peers asked for, enough to fill the connection slots.
+const NUMWANT: usize = 50;
+
+/// Transfer counters reported to the trackers, in bytes.
+#[derive(Debug, Default)]
+pub struct TransferStats {
+    pub uploaded: AtomicUsize,
+    pub downloaded: AtomicUsize,
+    pub left: AtomicUsize,
+}
+
+impl TransferStats {
+    pub fn new(left: usize) -> TransferStats {
+        TransferStats {
+            left: AtomicUsize::new(left),
+            ..Default::default()
+        }
+    }
+
+    /// Counts a piece which was downloaded and verified.
+    pub fn add_downloaded(&self, length: usize) {
+        self.downloaded.fetch_add(length, Ordering::Relaxed);
+        self.left.fetch_sub(length, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the trackers of a torrent informed for the whole life of a
+/// transfer: the events it goes through and regular announces in between.
+pub struct Announcer {
+    trackers: AnnounceList,
+    request: DiscoverPeersRequest,
+    stats: Arc<TransferStats>,
+    interval: Duration,
+}
+
+impl Announcer {
+    pub fn new(
+        tiers: Vec<Vec<String>>,
+        info_hash: [u8; 20],
+        stats: Arc<TransferStats>,
+    ) -> Announcer {
+        let request = DiscoverPeersRequest {
+            announce_url: String::new(),
+            info_hash,
+            peer_id: "00112233445566778899".to_string(),
+            port: LISTEN_PORT as i32,
+            uploaded: 0,
+            downloaded: 0,
+            left: 0,
+            compact: 1,
+            tracker_id: None,
+            event: None,
+            numwant: Some(NUMWANT),
+            key: Some(random_u32()),
+        };
+
+        Announcer {
+            trackers: AnnounceList::new(tiers),
+            request,
+            stats,
+            interval: DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Announces the current counters to the trackers, returning the peers
+    /// they sent.
+    pub async fn announce(&mut self, event: Option<AnnounceEvent>) -> anyhow::Result<Vec<Peer>> {
+        let request = DiscoverPeersRequest {
+            uploaded: self.stats.uploaded.load(Ordering::Relaxed),
+            downloaded: self.stats.downloaded.load(Ordering::Relaxed),
+            left: self.stats.left.load(Ordering::Relaxed),
+            event,
+            ..self.request.clone()
+        };
+
+        let announced = self.trackers.announce(&request).await?;
+        self.interval = announced
+            .interval
+            .unwrap_or(DEFAULT_INTERVAL)
+            .max(MIN_INTERVAL);
+
+        Ok(announced.peers)
+    }
+
+    /// Re-announces every interval the trackers asked for, but no more often
+    /// than every [`MIN_INTERVAL`], passing on the peers they return. Failed
+    /// announces are reported and retried on the next interval.
+    pub async fn keep_announcing(&mut self, peers: mpsc::UnboundedSender<Vec<Peer>>) -> Infallible {
+        loop {
+            tokio::time::sleep(self.interval).await;
+
+            match self.announce(None).await {
+                Ok(new_peers) => {
+                    let _ = peers.send(new_peers);
+                }
+                Err(err) => eprintln!("Could not re-announce: {}", err),
+            }
+        }
+    }
+}
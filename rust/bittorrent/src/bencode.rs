@@ -1,68 +1,814 @@
+use std::collections::BTreeMap;
+
 use serde_json::{self, Map};
+use thiserror::Error;
 
-fn decode_bencoded_value_rec(encoded_value: &str) -> (serde_json::Value, &str) {
-    let c = encoded_value.chars().next().expect("Found an empty string");
+use crate::encoding::{base64_decode, base64_encode};
 
-    match c {
-        '0'..='9' => {
-            let (letters_count, rest) = encoded_value
-                .split_once(':')
-                .expect("Expected an ':' but found none");
+/// Nesting depth after which decoding is aborted, protects the stack from
+/// malicious inputs such as `llllllll...`.
+const MAX_DEPTH: usize = 512;
 
-            let number = letters_count.parse::<i64>().unwrap() as usize;
-            let string = &rest[..number];
-            (
-                serde_json::Value::String(string.to_string()),
-                &rest[number..],
-            )
+/// JSON object key marking a byte string given in hex, e.g. `{"$hex": "00ff"}`.
+pub const JSON_HEX_TAG: &str = "$hex";
+
+/// JSON object key marking a byte string given in base64, e.g. `{"$base64": "AP8="}`.
+pub const JSON_BASE64_TAG: &str = "$base64";
+
+/// Number of bytes of a binary string shown in the tree rendering.
+const TREE_BYTES_PREVIEW: usize = 20;
+
+/// How byte strings which are not valid UTF-8 are rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BytesFormat {
+    /// `{"$hex": "..."}`
+    Hex,
+    /// `{"$base64": "..."}`
+    Base64,
+}
+
+impl BytesFormat {
+    fn tag(&self) -> &'static str {
+        match self {
+            BytesFormat::Hex => JSON_HEX_TAG,
+            BytesFormat::Base64 => JSON_BASE64_TAG,
+        }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            BytesFormat::Hex => hex::encode(bytes),
+            BytesFormat::Base64 => base64_encode(bytes),
+        }
+    }
+
+    /// Dictionary keys must be JSON strings, so binary keys are written as
+    /// the tag followed by a colon and the encoded bytes, e.g. `"$hex:00ff"`.
+    fn render_key(&self, key: &[u8]) -> String {
+        match std::str::from_utf8(key) {
+            Ok(key) => key.to_string(),
+            Err(_) => format!("{}:{}", self.tag(), self.encode(key)),
+        }
+    }
+
+    /// Like [`BytesFormat::render_key`], but text keys starting with `$`
+    /// get another `$` so that they cannot be taken for a tag, e.g. the
+    /// dictionary `d4:$hex2:abe` becomes `{"$$hex": "ab"}`.
+    fn json_key(&self, key: &[u8]) -> String {
+        match std::str::from_utf8(key) {
+            Ok(key) if key.starts_with('$') => format!("${}", key),
+            _ => self.render_key(key),
         }
-        'l' => {
-            let mut v: Vec<serde_json::Value> = Vec::new();
-            let mut str = &encoded_value[1..];
-            while str.chars().next().unwrap() != 'e' {
-                let (val, remaining) = decode_bencoded_value_rec(str);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Bytes(Vec<u8>),
+    Integer(i64),
+    List(Vec<BencodeValue>),
+    /// Keys are kept sorted as raw bytes, which is the canonical bencode order.
+    Dictionary(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FromJsonError {
+    #[error("{0}: null has no bencode representation")]
+    Null(String),
+    #[error("{0}: booleans have no bencode representation")]
+    Bool(String),
+    #[error("{0}: only integers that fit in 64 bits can be encoded")]
+    Number(String),
+    #[error("{0}: invalid hex byte string")]
+    InvalidHex(String),
+    #[error("{0}: invalid base64 byte string")]
+    InvalidBase64(String),
+}
+
+impl BencodeValue {
+    /// Encodes the value, dictionary keys are always written in sorted order.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
 
-                v.push(val);
-                str = remaining;
+        out
+    }
+
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        match self {
+            BencodeValue::Bytes(bytes) => {
+                out.extend_from_slice(bytes.len().to_string().as_bytes());
+                out.push(b':');
+                out.extend_from_slice(bytes);
+            }
+            BencodeValue::Integer(num) => {
+                out.push(b'i');
+                out.extend_from_slice(num.to_string().as_bytes());
+                out.push(b'e');
             }
+            BencodeValue::List(list) => {
+                out.push(b'l');
+                list.iter().for_each(|v| v.encode_to(out));
+                out.push(b'e');
+            }
+            BencodeValue::Dictionary(map) => {
+                out.push(b'd');
+                for (k, v) in map {
+                    BencodeValue::Bytes(k.clone()).encode_to(out);
+                    v.encode_to(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
 
-            (serde_json::Value::Array(v), &str[1..])
+    /// Converts to JSON. Byte strings which are valid UTF-8 become JSON strings,
+    /// any other byte string becomes a tagged object in the given format. With
+    /// the dictionary keys starting with `$` escaped, the output can be fed
+    /// back to [`BencodeValue::from_json`] without loss.
+    pub fn to_json(&self, format: BytesFormat) -> serde_json::Value {
+        match self {
+            BencodeValue::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => serde_json::Value::String(s.to_string()),
+                Err(_) => {
+                    let mut tagged = Map::new();
+                    tagged.insert(
+                        format.tag().to_string(),
+                        serde_json::Value::String(format.encode(bytes)),
+                    );
+                    serde_json::Value::Object(tagged)
+                }
+            },
+            BencodeValue::Integer(num) => serde_json::Value::Number((*num).into()),
+            BencodeValue::List(list) => {
+                serde_json::Value::Array(list.iter().map(|v| v.to_json(format)).collect())
+            }
+            BencodeValue::Dictionary(map) => serde_json::Value::Object(
+                map.iter()
+                    .map(|(k, v)| (format.json_key(k), v.to_json(format)))
+                    .collect::<Map<String, serde_json::Value>>(),
+            ),
         }
-        'd' => {
-            let mut map: Map<String, serde_json::Value> = Map::new();
-            let mut str = &encoded_value[1..];
-            while str.chars().next().unwrap() != 'e' {
-                let (key, remaining) = decode_bencoded_value_rec(str);
-                let (v, remaining) = decode_bencoded_value_rec(remaining);
+    }
+
+    /// Renders the value as an indented tree meant for humans, long binary
+    /// strings are truncated.
+    pub fn to_tree(&self, format: BytesFormat) -> String {
+        let mut out = String::new();
+        self.tree_to(&mut out, format, "");
+
+        out
+    }
+
+    fn tree_to(&self, out: &mut String, format: BytesFormat, indent: &str) {
+        match self {
+            BencodeValue::Bytes(bytes) => match std::str::from_utf8(bytes) {
+                Ok(s) => out.push_str(&format!("{:?}\n", s)),
+                Err(_) => {
+                    let preview = &bytes[..std::cmp::min(bytes.len(), TREE_BYTES_PREVIEW)];
+                    let ellipsis = if preview.len() < bytes.len() {
+                        "..."
+                    } else {
+                        ""
+                    };
+                    out.push_str(&format!(
+                        "<{} bytes> {}{}\n",
+                        bytes.len(),
+                        format.encode(preview),
+                        ellipsis
+                    ));
+                }
+            },
+            BencodeValue::Integer(num) => out.push_str(&format!("{}\n", num)),
+            BencodeValue::List(list) => {
+                out.push_str(&format!("list ({} items)\n", list.len()));
+                for (i, v) in list.iter().enumerate() {
+                    let last = i + 1 == list.len();
+                    Self::tree_child_to(out, format, indent, &format!("[{}]", i), v, last);
+                }
+            }
+            BencodeValue::Dictionary(map) => {
+                out.push_str(&format!("dict ({} keys)\n", map.len()));
+                for (i, (k, v)) in map.iter().enumerate() {
+                    let last = i + 1 == map.len();
+                    Self::tree_child_to(out, format, indent, &format.render_key(k), v, last);
+                }
+            }
+        }
+    }
+
+    fn tree_child_to(
+        out: &mut String,
+        format: BytesFormat,
+        indent: &str,
+        label: &str,
+        value: &BencodeValue,
+        last: bool,
+    ) {
+        let (branch, continuation) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+
+        out.push_str(&format!("{}{}{}: ", indent, branch, label));
+        value.tree_to(out, format, &format!("{}{}", indent, continuation));
+    }
+
+    /// Converts from JSON. Strings become their UTF-8 bytes, raw byte strings
+    /// can be given as `{"$hex": "..."}` or `{"$base64": "..."}`. A dictionary
+    /// key starting with `$$` stands for the key with a single `$`.
+    pub fn from_json(value: &serde_json::Value) -> Result<BencodeValue, FromJsonError> {
+        Self::from_json_at(value, "$")
+    }
+
+    fn from_json_at(value: &serde_json::Value, path: &str) -> Result<BencodeValue, FromJsonError> {
+        match value {
+            serde_json::Value::Null => Err(FromJsonError::Null(path.to_string())),
+            serde_json::Value::Bool(_) => Err(FromJsonError::Bool(path.to_string())),
+            serde_json::Value::Number(num) => num
+                .as_i64()
+                .map(BencodeValue::Integer)
+                .ok_or_else(|| FromJsonError::Number(path.to_string())),
+            serde_json::Value::String(s) => Ok(BencodeValue::Bytes(s.as_bytes().to_vec())),
+            serde_json::Value::Array(list) => list
+                .iter()
+                .enumerate()
+                .map(|(i, v)| Self::from_json_at(v, &format!("{}[{}]", path, i)))
+                .collect::<Result<Vec<_>, _>>()
+                .map(BencodeValue::List),
+            serde_json::Value::Object(map) => {
+                if let Some(bytes) = Self::tagged_bytes_from_json(map, path) {
+                    return bytes.map(BencodeValue::Bytes);
+                }
 
-                let k = key
-                    .as_str()
-                    .expect(&format!(
-                        "Expected the key to be a string but found {:?}",
-                        key
-                    ))
-                    .to_owned();
-                map.insert(k, v);
-                str = remaining;
+                map.iter()
+                    .map(|(k, v)| {
+                        let key = Self::key_from_json(k, path)?;
+                        Self::from_json_at(v, &format!("{}.{}", path, k)).map(|v| (key, v))
+                    })
+                    .collect::<Result<BTreeMap<_, _>, _>>()
+                    .map(BencodeValue::Dictionary)
             }
+        }
+    }
+
+    /// Reverses [`BytesFormat::json_key`].
+    fn key_from_json(key: &str, path: &str) -> Result<Vec<u8>, FromJsonError> {
+        let path = || format!("{}.{}", path, key);
 
-            (serde_json::Value::Object(map), &str[1..])
+        if let Some(escaped) = key.strip_prefix("$$") {
+            return Ok(format!("${}", escaped).into_bytes());
+        }
+
+        if let Some(encoded) = key.strip_prefix(&format!("{}:", JSON_HEX_TAG)) {
+            return hex::decode(encoded).map_err(|_| FromJsonError::InvalidHex(path()));
+        }
+        if let Some(encoded) = key.strip_prefix(&format!("{}:", JSON_BASE64_TAG)) {
+            return base64_decode(encoded).map_err(|_| FromJsonError::InvalidBase64(path()));
         }
-        'i' => {
-            let (str_num, rest) = encoded_value[1..]
-                .split_once('e')
-                .expect("Expected an 'e' at the end of the number but found none");
-            let num = str_num.parse::<i64>().unwrap();
-            (serde_json::Value::Number(num.into()), rest)
+
+        Ok(key.as_bytes().to_vec())
+    }
+
+    fn tagged_bytes_from_json(
+        map: &Map<String, serde_json::Value>,
+        path: &str,
+    ) -> Option<Result<Vec<u8>, FromJsonError>> {
+        if map.len() != 1 {
+            return None;
         }
-        _ => {
-            panic!("Unknown encoding: {}", c)
+
+        let (tag, value) = map.iter().next()?;
+        let value = value.as_str()?;
+        match tag.as_str() {
+            JSON_HEX_TAG => {
+                Some(hex::decode(value).map_err(|_| FromJsonError::InvalidHex(path.to_string())))
+            }
+            JSON_BASE64_TAG => Some(
+                base64_decode(value).map_err(|_| FromJsonError::InvalidBase64(path.to_string())),
+            ),
+            _ => None,
         }
     }
 }
 
-pub fn decode_bencoded_value(encoded_value: &str) -> serde_json::Value {
-    let result = decode_bencoded_value_rec(encoded_value);
-    eprintln!("result: {}, '{}'", result.0, result.1);
-    return decode_bencoded_value_rec(encoded_value).0;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeMode {
+    /// Accepts non-canonical integers and unsorted or duplicated dictionary keys.
+    Lenient,
+    /// Only accepts the canonical encoding: sorted and unique dictionary keys
+    /// and integers without leading zeros or negative zero.
+    Strict,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DecodeErrorReason {
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+    #[error("unexpected byte {0:#04x}")]
+    UnexpectedByte(u8),
+    #[error("string of length {expected} is truncated, only {available} bytes remain")]
+    TruncatedString { expected: usize, available: usize },
+    #[error("invalid string length")]
+    InvalidStringLength,
+    #[error("invalid integer")]
+    InvalidInteger,
+    #[error("leading zeros are not allowed")]
+    LeadingZero,
+    #[error("negative zero is not allowed")]
+    NegativeZero,
+    #[error("unterminated integer")]
+    UnterminatedInteger,
+    #[error("unterminated list")]
+    UnterminatedList,
+    #[error("unterminated dictionary")]
+    UnterminatedDictionary,
+    #[error("dictionary keys must be strings")]
+    NonStringKey,
+    #[error("dictionary keys are not sorted")]
+    UnsortedKeys,
+    #[error("duplicate dictionary key")]
+    DuplicateKey,
+    #[error("nesting is too deep")]
+    TooDeep,
+    #[error("trailing data after the value")]
+    TrailingData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{reason} at byte {position}")]
+pub struct DecodeError {
+    /// The offset in the input at which decoding failed.
+    pub position: usize,
+    pub reason: DecodeErrorReason,
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    position: usize,
+    mode: DecodeMode,
+    depth: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error<T>(&self, position: usize, reason: DecodeErrorReason) -> Result<T, DecodeError> {
+        Err(DecodeError { position, reason })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.position).copied()
+    }
+
+    /// Reads up to (and consumes) the terminator, returning the bytes before it.
+    fn read_until(
+        &mut self,
+        terminator: u8,
+        reason: DecodeErrorReason,
+    ) -> Result<&'a [u8], DecodeError> {
+        let rest = &self.input[self.position..];
+        match rest.iter().position(|b| *b == terminator) {
+            Some(len) => {
+                self.position += len + 1;
+                Ok(&rest[..len])
+            }
+            None => self.error(self.input.len(), reason),
+        }
+    }
+
+    fn decode_value(&mut self) -> Result<BencodeValue, DecodeError> {
+        match self.peek() {
+            None => self.error(self.position, DecodeErrorReason::UnexpectedEnd),
+            Some(b'0'..=b'9') => Ok(BencodeValue::Bytes(self.decode_string()?.to_vec())),
+            Some(b'i') => self.decode_integer(),
+            Some(b'l') => self.nested(Self::decode_list),
+            Some(b'd') => self.nested(Self::decode_dictionary),
+            Some(c) => self.error(self.position, DecodeErrorReason::UnexpectedByte(c)),
+        }
+    }
+
+    fn nested(
+        &mut self,
+        decode: fn(&mut Self) -> Result<BencodeValue, DecodeError>,
+    ) -> Result<BencodeValue, DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return self.error(self.position, DecodeErrorReason::TooDeep);
+        }
+
+        self.depth += 1;
+        let value = decode(self);
+        self.depth -= 1;
+
+        value
+    }
+
+    fn decode_string(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.position;
+        let digits = self.read_until(b':', DecodeErrorReason::UnexpectedEnd)?;
+
+        if digits.len() > 1 && digits[0] == b'0' {
+            return self.error(start, DecodeErrorReason::LeadingZero);
+        }
+
+        let length: usize = match std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse().ok())
+        {
+            Some(length) if digits.iter().all(u8::is_ascii_digit) => length,
+            _ => return self.error(start, DecodeErrorReason::InvalidStringLength),
+        };
+
+        let available = self.input.len() - self.position;
+        if length > available {
+            return self.error(
+                start,
+                DecodeErrorReason::TruncatedString {
+                    expected: length,
+                    available,
+                },
+            );
+        }
+
+        let string = &self.input[self.position..self.position + length];
+        self.position += length;
+
+        Ok(string)
+    }
+
+    fn decode_integer(&mut self) -> Result<BencodeValue, DecodeError> {
+        let start = self.position;
+        self.position += 1;
+        let digits = self.read_until(b'e', DecodeErrorReason::UnterminatedInteger)?;
+
+        let num = match std::str::from_utf8(digits)
+            .ok()
+            .and_then(|s| s.parse::<i64>().ok())
+        {
+            Some(num) if !digits.starts_with(b"+") => num,
+            _ => return self.error(start, DecodeErrorReason::InvalidInteger),
+        };
+
+        if self.mode == DecodeMode::Strict {
+            let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+            if unsigned.len() > 1 && unsigned[0] == b'0' {
+                return self.error(start, DecodeErrorReason::LeadingZero);
+            }
+            if digits == b"-0" {
+                return self.error(start, DecodeErrorReason::NegativeZero);
+            }
+        }
+
+        Ok(BencodeValue::Integer(num))
+    }
+
+    fn decode_list(&mut self) -> Result<BencodeValue, DecodeError> {
+        self.position += 1;
+
+        let mut v: Vec<BencodeValue> = Vec::new();
+        loop {
+            match self.peek() {
+                None => return self.error(self.position, DecodeErrorReason::UnterminatedList),
+                Some(b'e') => break,
+                Some(_) => v.push(self.decode_value()?),
+            }
+        }
+        self.position += 1;
+
+        Ok(BencodeValue::List(v))
+    }
+
+    fn decode_dictionary(&mut self) -> Result<BencodeValue, DecodeError> {
+        self.position += 1;
+
+        let mut map: BTreeMap<Vec<u8>, BencodeValue> = BTreeMap::new();
+        let mut previous_key: Option<&[u8]> = None;
+        loop {
+            let key_position = self.position;
+            let key = match self.peek() {
+                None => {
+                    return self.error(self.position, DecodeErrorReason::UnterminatedDictionary)
+                }
+                Some(b'e') => break,
+                Some(b'0'..=b'9') => self.decode_string()?,
+                Some(_) => return self.error(self.position, DecodeErrorReason::NonStringKey),
+            };
+
+            if self.mode == DecodeMode::Strict {
+                match previous_key {
+                    Some(prev) if prev == key => {
+                        return self.error(key_position, DecodeErrorReason::DuplicateKey)
+                    }
+                    Some(prev) if prev > key => {
+                        return self.error(key_position, DecodeErrorReason::UnsortedKeys)
+                    }
+                    _ => {}
+                }
+            }
+            previous_key = Some(key);
+
+            if self.peek().is_none() {
+                return self.error(self.position, DecodeErrorReason::UnterminatedDictionary);
+            }
+            let v = self.decode_value()?;
+            map.insert(key.to_vec(), v);
+        }
+        self.position += 1;
+
+        Ok(BencodeValue::Dictionary(map))
+    }
+}
+
+/// Decodes a single bencoded value spanning the whole input.
+pub fn decode_bencoded_value(
+    encoded_value: &[u8],
+    mode: DecodeMode,
+) -> Result<BencodeValue, DecodeError> {
+    let (value, length) = decode_bencoded_prefix(encoded_value, mode)?;
+    if length != encoded_value.len() {
+        return Err(DecodeError {
+            position: length,
+            reason: DecodeErrorReason::TrailingData,
+        });
+    }
+
+    Ok(value)
+}
+
+/// Decodes the bencoded value at the start of the input, returning it with
+/// the number of bytes it spans. Used for messages that append raw data
+/// after a bencoded dictionary.
+pub fn decode_bencoded_prefix(
+    encoded_value: &[u8],
+    mode: DecodeMode,
+) -> Result<(BencodeValue, usize), DecodeError> {
+    let mut decoder = Decoder {
+        input: encoded_value,
+        position: 0,
+        mode,
+        depth: 0,
+    };
+
+    let value = decoder.decode_value()?;
+
+    Ok((value, decoder.position))
+}
+
+/// Returns the exact bytes of the value stored under `key` in a bencoded
+/// dictionary, without re-encoding it. The info hash of a torrent must be
+/// computed over these original bytes.
+pub fn find_raw_dictionary_value<'a>(
+    encoded_value: &'a [u8],
+    key: &[u8],
+) -> Result<Option<&'a [u8]>, DecodeError> {
+    let mut decoder = Decoder {
+        input: encoded_value,
+        position: 0,
+        mode: DecodeMode::Lenient,
+        depth: 1,
+    };
+
+    match decoder.peek() {
+        Some(b'd') => decoder.position += 1,
+        Some(c) => return decoder.error(0, DecodeErrorReason::UnexpectedByte(c)),
+        None => return decoder.error(0, DecodeErrorReason::UnexpectedEnd),
+    }
+
+    loop {
+        let current_key = match decoder.peek() {
+            None => {
+                return decoder.error(decoder.position, DecodeErrorReason::UnterminatedDictionary)
+            }
+            Some(b'e') => return Ok(None),
+            Some(b'0'..=b'9') => decoder.decode_string()?,
+            Some(_) => return decoder.error(decoder.position, DecodeErrorReason::NonStringKey),
+        };
+
+        let start = decoder.position;
+        decoder.decode_value()?;
+
+        if current_key == key {
+            return Ok(Some(&encoded_value[start..decoder.position]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_error(input: &[u8], mode: DecodeMode) -> (usize, DecodeErrorReason) {
+        let err = decode_bencoded_value(input, mode).unwrap_err();
+
+        (err.position, err.reason)
+    }
+
+    #[test]
+    fn decodes_nested_values() {
+        let value = decode_bencoded_value(b"d3:bar4:spam3:fooli42ei-3eee", DecodeMode::Strict);
+
+        assert_eq!(
+            value,
+            Ok(BencodeValue::Dictionary(BTreeMap::from([
+                (b"bar".to_vec(), BencodeValue::Bytes(b"spam".to_vec())),
+                (
+                    b"foo".to_vec(),
+                    BencodeValue::List(vec![BencodeValue::Integer(42), BencodeValue::Integer(-3)])
+                ),
+            ])))
+        );
+    }
+
+    #[test]
+    fn encode_reverses_decode() {
+        let input = b"d4:infod6:lengthi12e4:name5:a.txte4:listl0:i0eee";
+        let value = decode_bencoded_value(input, DecodeMode::Strict).unwrap();
+
+        assert_eq!(value.encode(), input.to_vec());
+    }
+
+    #[test]
+    fn json_round_trips_dictionaries_looking_like_tags() {
+        for input in [
+            &b"d4:$hex2:abe"[..],
+            b"d7:$base644:AP8=e",
+            b"d7:$hex:004:datae",
+            b"d5:$$hexi1ee",
+            b"d1:\xffl2:\x00\x01ee",
+        ] {
+            let value = decode_bencoded_value(input, DecodeMode::Strict).unwrap();
+            let json = value.to_json(BytesFormat::Hex);
+
+            assert_eq!(BencodeValue::from_json(&json), Ok(value), "{}", json);
+        }
+    }
+
+    #[test]
+    fn json_escapes_keys_starting_with_a_dollar() {
+        let value = decode_bencoded_value(b"d4:$hex2:abe", DecodeMode::Strict).unwrap();
+
+        assert_eq!(
+            value.to_json(BytesFormat::Hex).to_string(),
+            r#"{"$$hex":"ab"}"#
+        );
+    }
+
+    #[test]
+    fn truncated_string() {
+        assert_eq!(
+            decode_error(b"l5:abce", DecodeMode::Lenient),
+            (
+                1,
+                DecodeErrorReason::TruncatedString {
+                    expected: 5,
+                    available: 4
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn string_length_with_leading_zero() {
+        assert_eq!(
+            decode_error(b"03:abc", DecodeMode::Lenient),
+            (0, DecodeErrorReason::LeadingZero)
+        );
+    }
+
+    #[test]
+    fn integer_with_leading_zero_is_only_rejected_when_strict() {
+        assert_eq!(
+            decode_error(b"li1ei03ee", DecodeMode::Strict),
+            (4, DecodeErrorReason::LeadingZero)
+        );
+        assert_eq!(
+            decode_bencoded_value(b"i03e", DecodeMode::Lenient),
+            Ok(BencodeValue::Integer(3))
+        );
+    }
+
+    #[test]
+    fn negative_zero_is_only_rejected_when_strict() {
+        assert_eq!(
+            decode_error(b"i-0e", DecodeMode::Strict),
+            (0, DecodeErrorReason::NegativeZero)
+        );
+        assert_eq!(
+            decode_bencoded_value(b"i-0e", DecodeMode::Lenient),
+            Ok(BencodeValue::Integer(0))
+        );
+    }
+
+    #[test]
+    fn invalid_integers() {
+        assert_eq!(
+            decode_error(b"i+1e", DecodeMode::Lenient),
+            (0, DecodeErrorReason::InvalidInteger)
+        );
+        assert_eq!(
+            decode_error(b"i12", DecodeMode::Lenient),
+            (3, DecodeErrorReason::UnterminatedInteger)
+        );
+    }
+
+    #[test]
+    fn unterminated_list() {
+        assert_eq!(
+            decode_error(b"li1e", DecodeMode::Lenient),
+            (4, DecodeErrorReason::UnterminatedList)
+        );
+    }
+
+    #[test]
+    fn unterminated_dictionary() {
+        assert_eq!(
+            decode_error(b"d1:a", DecodeMode::Lenient),
+            (4, DecodeErrorReason::UnterminatedDictionary)
+        );
+    }
+
+    #[test]
+    fn non_string_key() {
+        assert_eq!(
+            decode_error(b"di1e1:ae", DecodeMode::Lenient),
+            (1, DecodeErrorReason::NonStringKey)
+        );
+    }
+
+    #[test]
+    fn unsorted_keys_are_only_rejected_when_strict() {
+        let input = b"d1:bi1e1:ai2ee";
+
+        assert_eq!(
+            decode_error(input, DecodeMode::Strict),
+            (7, DecodeErrorReason::UnsortedKeys)
+        );
+        assert!(decode_bencoded_value(input, DecodeMode::Lenient).is_ok());
+    }
+
+    #[test]
+    fn duplicate_keys_are_only_rejected_when_strict() {
+        let input = b"d1:ai1e1:ai2ee";
+
+        assert_eq!(
+            decode_error(input, DecodeMode::Strict),
+            (7, DecodeErrorReason::DuplicateKey)
+        );
+        assert_eq!(
+            decode_bencoded_value(input, DecodeMode::Lenient),
+            Ok(BencodeValue::Dictionary(BTreeMap::from([(
+                b"a".to_vec(),
+                BencodeValue::Integer(2)
+            )])))
+        );
+    }
+
+    #[test]
+    fn nesting_deeper_than_the_limit() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+
+        assert!(decode_bencoded_value(&nested(MAX_DEPTH), DecodeMode::Lenient).is_ok());
+        assert_eq!(
+            decode_error(&nested(MAX_DEPTH + 1), DecodeMode::Lenient),
+            (MAX_DEPTH, DecodeErrorReason::TooDeep)
+        );
+    }
+
+    #[test]
+    fn trailing_data() {
+        assert_eq!(
+            decode_error(b"i1ei2e", DecodeMode::Lenient),
+            (3, DecodeErrorReason::TrailingData)
+        );
+    }
+
+    #[test]
+    fn unexpected_byte() {
+        let err = decode_bencoded_value(b"x", DecodeMode::Lenient).unwrap_err();
+
+        assert_eq!(err.reason, DecodeErrorReason::UnexpectedByte(b'x'));
+        assert_eq!(err.to_string(), "unexpected byte 0x78 at byte 0");
+    }
+
+    #[test]
+    fn prefix_returns_the_length_of_the_value() {
+        let (value, length) =
+            decode_bencoded_prefix(b"d1:ai1ee<raw data>", DecodeMode::Lenient).unwrap();
+
+        assert_eq!(length, 8);
+        assert_eq!(
+            value,
+            BencodeValue::Dictionary(BTreeMap::from([(b"a".to_vec(), BencodeValue::Integer(1))]))
+        );
+    }
+
+    #[test]
+    fn raw_dictionary_value() {
+        let input = b"d8:announce3:url4:infod6:lengthi1eee";
+
+        assert_eq!(
+            find_raw_dictionary_value(input, b"info"),
+            Ok(Some(&b"d6:lengthi1ee"[..]))
+        );
+        assert_eq!(find_raw_dictionary_value(input, b"missing"), Ok(None));
+    }
 }
@@ -0,0 +1,109 @@
+/// The pieces a peer has, as sent in a `Bitfield` message: the high bit of
+/// the first byte is piece 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+}
+
+impl Bitfield {
+    /// A bitfield with none of the pieces set.
+    pub fn new(no_of_pieces: usize) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; (no_of_pieces + 7) / 8],
+        }
+    }
+
+    /// Checks a payload received from a peer: it must have exactly one bit
+    /// per piece, with the spare bits of the last byte cleared.
+    pub fn from_payload(payload: Vec<u8>, no_of_pieces: usize) -> anyhow::Result<Bitfield> {
+        let expected = (no_of_pieces + 7) / 8;
+        if payload.len() != expected {
+            anyhow::bail!(
+                "Expected a bitfield of {} bytes but got {}",
+                expected,
+                payload.len()
+            );
+        }
+
+        let spare_bits = expected * 8 - no_of_pieces;
+        if spare_bits > 0 && payload[expected - 1] & ((1 << spare_bits) - 1) != 0 {
+            anyhow::bail!("Bitfield has pieces set past the last piece");
+        }
+
+        Ok(Bitfield { bytes: payload })
+    }
+
+    /// A bitfield with every one of the pieces set.
+    pub fn full(no_of_pieces: usize) -> Bitfield {
+        let mut bitfield = Bitfield::new(no_of_pieces);
+        (0..no_of_pieces).for_each(|piece_index| bitfield.set(piece_index));
+
+        bitfield
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    /// Marks a piece as available, the bitfield never grows so `piece_index`
+    /// must be checked against the number of pieces beforehand.
+    pub fn set(&mut self, piece_index: usize) {
+        self.bytes[piece_index / 8] |= 0x80 >> (piece_index % 8);
+    }
+
+    pub fn clear(&mut self, piece_index: usize) {
+        self.bytes[piece_index / 8] &= !(0x80 >> (piece_index % 8));
+    }
+
+    pub fn has(&self, piece_index: usize) -> bool {
+        self.bytes
+            .get(piece_index / 8)
+            .map(|byte| byte & (0x80 >> (piece_index % 8)) != 0)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_payload_accepts_one_bit_per_piece() {
+        let bitfield = Bitfield::from_payload(vec![0b1010_0000, 0b0100_0000], 10).unwrap();
+
+        let pieces: Vec<usize> = (0..10).filter(|i| bitfield.has(*i)).collect();
+        assert_eq!(pieces, vec![0, 2, 9]);
+        assert_eq!(bitfield.to_payload(), vec![0b1010_0000, 0b0100_0000]);
+    }
+
+    #[test]
+    fn from_payload_rejects_the_wrong_length() {
+        for payload in [vec![0xff], vec![0xff, 0xc0, 0x00]] {
+            let err = Bitfield::from_payload(payload, 10).unwrap_err();
+
+            assert!(
+                err.to_string()
+                    .starts_with("Expected a bitfield of 2 bytes"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn from_payload_rejects_spare_bits() {
+        assert!(Bitfield::from_payload(vec![0xff, 0xc0], 10).is_ok());
+        assert!(Bitfield::from_payload(vec![0xff, 0xe0], 10).is_err());
+        assert!(Bitfield::from_payload(vec![0xff, 0x01], 10).is_err());
+        // no spare bits when the pieces fill the last byte
+        assert!(Bitfield::from_payload(vec![0xff, 0xff], 16).is_ok());
+    }
+
+    #[test]
+    fn full_sets_only_existing_pieces() {
+        let bitfield = Bitfield::full(10);
+
+        assert_eq!(bitfield.to_payload(), vec![0xff, 0xc0]);
+        assert!(!bitfield.has(10));
+    }
+}
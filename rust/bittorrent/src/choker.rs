@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, Notify};
+
+use crate::announcer::TransferStats;
+use crate::random::random_u64;
+
+/// The number of peers uploaded to at the same time, including the
+/// optimistic unchoke, when not configured otherwise.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+const OPTIMISTIC_UNCHOKE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A connection as seen by the [`Choker`], the connection keeps the counter
+/// up to date and receives whether to choke the peer over a channel.
+pub struct ChokedPeer {
+    peer_id: [u8; 20],
+    interested: AtomicBool,
+
+    /// Bytes sent to the peer.
+    pub uploaded: AtomicUsize,
+
+    /// `true` to choke the peer, `false` to unchoke it.
+    commands: mpsc::UnboundedSender<bool>,
+}
+
+/// What the choker keeps about every registered peer between rounds.
+struct PeerState {
+    peer: Arc<ChokedPeer>,
+    unchoked: bool,
+
+    /// The counter taken into account by the last round, and the number of
+    /// bytes transferred since the round before it.
+    last_transferred: usize,
+    rate: usize,
+}
+
+struct ChokerState {
+    peers: Vec<PeerState>,
+
+    /// Bytes downloaded from each peer over the connections we opened, as
+    /// the peers we upload to connected to us.
+    downloaded: HashMap<[u8; 20], usize>,
+
+    optimistic: Option<Arc<ChokedPeer>>,
+    last_rotation: Option<Instant>,
+}
+
+/// Decides which peers we upload to (tit-for-tat): every 10 seconds the
+/// interested peers which gave us the most in return are unchoked, along
+/// with one optimistic unchoke rotated every 30 seconds so new peers get a
+/// chance to show what they are worth.
+pub struct Choker {
+    upload_slots: usize,
+    state: Mutex<ChokerState>,
+
+    /// Wakes the choker up when a peer changed its interest.
+    changed: Notify,
+}
+
+impl Choker {
+    pub fn new(upload_slots: usize) -> Choker {
+        Choker {
+            upload_slots: upload_slots.max(1),
+            state: Mutex::new(ChokerState {
+                peers: vec![],
+                downloaded: HashMap::new(),
+                optimistic: None,
+                last_rotation: None,
+            }),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Adds a connection, starting choked.
+    pub fn register(&self, peer_id: [u8; 20]) -> (Arc<ChokedPeer>, mpsc::UnboundedReceiver<bool>) {
+        let (commands, rx) = mpsc::unbounded_channel();
+        let peer = Arc::new(ChokedPeer {
+            peer_id,
+            interested: AtomicBool::new(false),
+            uploaded: AtomicUsize::new(0),
+            commands,
+        });
+
+        self.state.lock().unwrap().peers.push(PeerState {
+            peer: peer.clone(),
+            unchoked: false,
+            last_transferred: 0,
+            rate: 0,
+        });
+
+        (peer, rx)
+    }
+
+    /// Removes a connection, freeing its upload slot.
+    pub fn unregister(&self, peer: &Arc<ChokedPeer>) {
+        self.state
+            .lock()
+            .unwrap()
+            .peers
+            .retain(|state| !Arc::ptr_eq(&state.peer, peer));
+        self.changed.notify_one();
+    }
+
+    /// Credits a peer with a piece we downloaded from it, which is what the
+    /// peer is ranked by while we are downloading.
+    pub fn add_downloaded(&self, peer_id: [u8; 20], length: usize) {
+        *self
+            .state
+            .lock()
+            .unwrap()
+            .downloaded
+            .entry(peer_id)
+            .or_default() += length;
+    }
+
+    /// Lets the choker know a peer became interested or not interested, so
+    /// that slots are given out without waiting for the next round.
+    pub fn set_interested(&self, peer: &ChokedPeer, interested: bool) {
+        peer.interested.store(interested, Ordering::Relaxed);
+        self.changed.notify_one();
+    }
+
+    /// Runs the rounds for as long as the transfer lasts. Once nothing is
+    /// `left`, peers are ranked by how fast they download from us instead.
+    pub async fn run(&self, stats: &TransferStats) -> Infallible {
+        let mut next_round = Instant::now();
+
+        loop {
+            let rated = Instant::now() >= next_round;
+            if rated {
+                next_round = Instant::now() + RECHOKE_INTERVAL;
+            }
+
+            let seeding = stats.left.load(Ordering::Relaxed) == 0;
+            self.rechoke(rated, seeding);
+
+            let _ = tokio::time::timeout(
+                next_round.saturating_duration_since(Instant::now()),
+                self.changed.notified(),
+            )
+            .await;
+        }
+    }
+
+    /// Picks the peers to unchoke. Rates are only updated by the regular
+    /// rounds, rounds caused by a change of interest reuse the last ones.
+    fn rechoke(&self, update_rates: bool, seeding: bool) {
+        let mut state = self.state.lock().unwrap();
+
+        if update_rates {
+            let ChokerState {
+                peers, downloaded, ..
+            } = &mut *state;
+
+            for peer_state in peers {
+                let transferred = if seeding {
+                    peer_state.peer.uploaded.load(Ordering::Relaxed)
+                } else {
+                    downloaded
+                        .get(&peer_state.peer.peer_id)
+                        .copied()
+                        .unwrap_or(0)
+                };
+
+                // the counter changes once the download completes
+                peer_state.rate = transferred.saturating_sub(peer_state.last_transferred);
+                peer_state.last_transferred = transferred;
+            }
+        }
+
+        let mut interested: Vec<usize> = (0..state.peers.len())
+            .filter(|i| state.peers[*i].peer.interested.load(Ordering::Relaxed))
+            .collect();
+        interested.sort_by(|a, b| state.peers[*b].rate.cmp(&state.peers[*a].rate));
+
+        // the best peers get the regular slots, the others compete for
+        // the optimistic one
+        let regular_slots = (self.upload_slots - 1).min(interested.len());
+        let (regular, candidates) = interested.split_at(regular_slots);
+        let mut unchoke = regular.to_vec();
+
+        let current = state.optimistic.as_ref().and_then(|optimistic| {
+            candidates
+                .iter()
+                .copied()
+                .find(|i| Arc::ptr_eq(&state.peers[*i].peer, optimistic))
+        });
+        let rotate = state
+            .last_rotation
+            .map_or(true, |last| last.elapsed() >= OPTIMISTIC_UNCHOKE_INTERVAL);
+
+        let optimistic = match current {
+            Some(i) if !rotate => Some(i),
+            _ if candidates.is_empty() => None,
+            _ => {
+                state.last_rotation = Some(Instant::now());
+                Some(candidates[(random_u64() % candidates.len() as u64) as usize])
+            }
+        };
+        state.optimistic = optimistic.map(|i| state.peers[i].peer.clone());
+        unchoke.extend(optimistic);
+
+        for (i, peer_state) in state.peers.iter_mut().enumerate() {
+            let unchoked = unchoke.contains(&i);
+            if unchoked != peer_state.unchoked {
+                peer_state.unchoked = unchoked;
+                let _ = peer_state.peer.commands.send(!unchoked);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    /// Registers interested peers with the ids 1, 2, ...
+    fn interested_peers(choker: &Choker, count: u8) -> Vec<Arc<ChokedPeer>> {
+        (1..=count)
+            .map(|id| {
+                let (peer, _) = choker.register([id; 20]);
+                choker.set_interested(&peer, true);
+                peer
+            })
+            .collect()
+    }
+
+    fn unchoked(choker: &Choker) -> BTreeSet<u8> {
+        let state = choker.state.lock().unwrap();
+
+        state
+            .peers
+            .iter()
+            .filter(|peer_state| peer_state.unchoked)
+            .map(|peer_state| peer_state.peer.peer_id[0])
+            .collect()
+    }
+
+    fn optimistic(choker: &Choker) -> Option<u8> {
+        let state = choker.state.lock().unwrap();
+
+        state.optimistic.as_ref().map(|peer| peer.peer_id[0])
+    }
+
+    #[test]
+    fn ranks_by_download_rate_while_leeching() {
+        let choker = Choker::new(3);
+        let peers = interested_peers(&choker, 4);
+        for (id, downloaded) in [(1, 100), (2, 300), (3, 200), (4, 0)] {
+            choker.add_downloaded([id; 20], downloaded);
+        }
+        // what we uploaded does not count while leeching
+        peers[3].uploaded.store(1000, Ordering::Relaxed);
+
+        choker.rechoke(true, false);
+
+        let unchoked = unchoked(&choker);
+        let optimistic = optimistic(&choker).unwrap();
+        assert!([1, 4].contains(&optimistic));
+        assert_eq!(unchoked, BTreeSet::from([2, 3, optimistic]));
+    }
+
+    #[test]
+    fn ranks_by_upload_rate_while_seeding() {
+        let choker = Choker::new(3);
+        let peers = interested_peers(&choker, 4);
+        for (peer, uploaded) in peers.iter().zip([300, 0, 100, 200]) {
+            peer.uploaded.store(uploaded, Ordering::Relaxed);
+        }
+        choker.add_downloaded([2; 20], 1000);
+
+        choker.rechoke(true, true);
+
+        let unchoked = unchoked(&choker);
+        let optimistic = optimistic(&choker).unwrap();
+        assert!([2, 3].contains(&optimistic));
+        assert_eq!(unchoked, BTreeSet::from([1, 4, optimistic]));
+    }
+
+    #[test]
+    fn rates_are_the_bytes_since_the_last_round() {
+        let choker = Choker::new(2);
+        interested_peers(&choker, 2);
+        choker.add_downloaded([1; 20], 1000);
+        choker.rechoke(true, false);
+
+        choker.add_downloaded([2; 20], 500);
+        choker.rechoke(true, false);
+
+        // peer 1 got nothing to us since the first round
+        assert_eq!(optimistic(&choker), Some(1));
+        assert_eq!(unchoked(&choker), BTreeSet::from([1, 2]));
+    }
+
+    #[test]
+    fn uninterested_peers_stay_choked() {
+        let choker = Choker::new(4);
+        let peers = interested_peers(&choker, 2);
+        choker.add_downloaded([1; 20], 100);
+        choker.set_interested(&peers[0], false);
+
+        choker.rechoke(true, false);
+
+        assert_eq!(unchoked(&choker), BTreeSet::from([2]));
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_interval() {
+        let choker = Choker::new(2);
+        interested_peers(&choker, 4);
+        choker.add_downloaded([1; 20], 100);
+        choker.rechoke(true, false);
+        let first = optimistic(&choker).unwrap();
+
+        // kept until the interval is over
+        for _ in 0..8 {
+            choker.rechoke(true, false);
+            assert_eq!(optimistic(&choker), Some(first));
+        }
+
+        let mut picked = BTreeSet::new();
+        for _ in 0..64 {
+            choker.state.lock().unwrap().last_rotation =
+                Some(Instant::now() - OPTIMISTIC_UNCHOKE_INTERVAL);
+            choker.rechoke(false, false);
+
+            let optimistic = optimistic(&choker).unwrap();
+            assert_eq!(unchoked(&choker), BTreeSet::from([1, optimistic]));
+            picked.insert(optimistic);
+        }
+
+        assert_eq!(picked, BTreeSet::from([2, 3, 4]));
+    }
+
+    #[test]
+    fn sends_only_changes_to_the_connections() {
+        let choker = Choker::new(1);
+        let (peer, mut commands) = choker.register([1; 20]);
+
+        choker.rechoke(true, false);
+        choker.set_interested(&peer, true);
+        choker.rechoke(false, false);
+        choker.rechoke(false, false);
+        choker.set_interested(&peer, false);
+        choker.rechoke(false, false);
+
+        assert_eq!(commands.try_recv(), Ok(false));
+        assert_eq!(commands.try_recv(), Ok(true));
+        assert!(commands.try_recv().is_err());
+    }
+}
@@ -2,6 +2,12 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 
+use crate::bencode::BytesFormat;
+use crate::choker::DEFAULT_UPLOAD_SLOTS;
+use crate::download::DownloadOptions;
+use crate::peer_session::DEFAULT_PIPELINE_DEPTH;
+use crate::storage::FsyncPolicy;
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
@@ -13,7 +19,29 @@ pub struct Args {
 #[clap(rename_all = "snake_case")]
 pub enum Command {
     Decode {
+        #[arg(required_unless_present = "file")]
+        value: Option<String>,
+        /// Read the bencoded value from a file, e.g. a .torrent or a saved tracker response.
+        #[arg(short, long, conflicts_with = "value")]
+        file: Option<PathBuf>,
+        /// Reject non-canonical encodings (unsorted keys, leading zeros, ...).
+        #[arg(long)]
+        strict: bool,
+        /// How byte strings which are not valid UTF-8 are rendered.
+        #[arg(long, value_enum, default_value_t = BytesFormat::Hex)]
+        bytes: BytesFormat,
+        /// Print an indented tree instead of JSON.
+        #[arg(long)]
+        pretty: bool,
+    },
+    /// Encodes a JSON value as bencode, raw byte strings can be given
+    /// as `{"$hex": "..."}` or `{"$base64": "..."}` and dictionary keys
+    /// starting with `$` as `$$...`.
+    Encode {
         value: String,
+        /// Write the bencoded value to a file instead of stdout.
+        #[arg(short)]
+        output: Option<PathBuf>,
     },
     Info {
         filename: PathBuf,
@@ -30,10 +58,77 @@ pub enum Command {
         output: PathBuf,
         torrent: String,
         piece_index: usize,
+        #[command(flatten)]
+        options: DownloadArgs,
     },
     Download {
         #[arg(short)]
         output: PathBuf,
         torrent: String,
+        #[command(flatten)]
+        options: DownloadArgs,
+    },
+    MagnetParse {
+        magnet_link: String,
+    },
+    /// Fetches the info dictionary of a magnet link from peers and prints it.
+    MagnetInfo {
+        magnet_link: String,
+    },
+    MagnetDownload {
+        #[arg(short)]
+        output: PathBuf,
+        magnet_link: String,
+        #[command(flatten)]
+        options: DownloadArgs,
+    },
+    /// Prints a magnet link for a torrent file.
+    Magnet {
+        filename: PathBuf,
+    },
+    /// Uploads a completely downloaded torrent to the peers connecting to us.
+    Seed {
+        torrent: String,
+        /// The file of a single-file torrent or the directory of a multi-file one.
+        path: PathBuf,
+        /// The number of peers uploaded to at the same time.
+        #[arg(long = "upload-slots", default_value_t = DEFAULT_UPLOAD_SLOTS)]
+        upload_slots: usize,
+    },
+    /// Prints the seeders, leechers and downloads the trackers of the torrents
+    /// report, without joining the swarms.
+    Scrape {
+        #[arg(required = true)]
+        torrents: Vec<PathBuf>,
     },
+    /// Checks data on disk against the piece hashes of a torrent, exiting
+    /// with an error if any piece is bad.
+    Verify {
+        torrent: PathBuf,
+        /// The file of a single-file torrent or the directory of a multi-file one.
+        path: PathBuf,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DownloadArgs {
+    /// The maximum number of block requests kept in flight per peer.
+    #[arg(long, default_value_t = DEFAULT_PIPELINE_DEPTH)]
+    pub pipeline: usize,
+
+    /// When downloaded pieces are flushed to the disk.
+    #[arg(long, value_enum, default_value_t = FsyncPolicy::Completion)]
+    pub fsync: FsyncPolicy,
+
+    /// The number of peers uploaded to at the same time while downloading.
+    #[arg(long, default_value_t = DEFAULT_UPLOAD_SLOTS)]
+    pub upload_slots: usize,
+}
+
+impl From<DownloadArgs> for DownloadOptions {
+    fn from(args: DownloadArgs) -> Self {
+        DownloadOptions {
+            pipeline_depth: args.pipeline,
+        }
+    }
 }
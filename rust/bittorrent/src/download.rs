@@ -0,0 +1,494 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinSet;
+
+use crate::bitfield::Bitfield;
+use crate::hash::hex_sha1;
+use crate::peer_message::{MessageType, PeerMessage};
+use crate::peer_session::{PeerSession, UNCHOKE_TIMEOUT};
+use crate::piece_download::PieceDownload;
+use crate::random::random_u64;
+use crate::torrent::TorrentFile;
+use crate::trackers::Peer;
+
+/// Upper bound on the number of peers downloaded from at the same time.
+const MAX_PEER_CONNECTIONS: usize = 30;
+
+/// How long an idle worker waits before looking at the queue again.
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for more peers once all of them failed, so that peers
+/// which keep failing at once are not reconnected to in a loop.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings shared by every peer of a download.
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// The maximum number of block requests kept in flight per peer.
+    pub pipeline_depth: usize,
+}
+
+/// A verified piece and the peer id of the peer it was downloaded from.
+pub struct DownloadedPiece {
+    pub index: usize,
+    pub data: Vec<u8>,
+    pub peer_id: [u8; 20],
+}
+
+/// Pieces waiting for a peer to download them, shared by all the workers.
+struct PieceQueue {
+    torrent: Arc<TorrentFile>,
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+struct QueueState {
+    pending: Vec<usize>,
+
+    /// The number of workers downloading each piece of the torrent, more
+    /// than one in endgame mode.
+    downloaders: Vec<usize>,
+
+    /// The pieces being downloaded, shared by their downloaders.
+    downloads: HashMap<usize, Arc<PieceDownload>>,
+    completed: Vec<bool>,
+
+    /// The number of connected peers having each piece of the torrent.
+    availability: Vec<usize>,
+
+    /// Whether a piece was downloaded yet, until then pieces are picked at
+    /// random rather than rarest first.
+    completed_any: bool,
+}
+
+impl PieceQueue {
+    fn new(torrent: Arc<TorrentFile>, pieces: impl IntoIterator<Item = usize>) -> PieceQueue {
+        let no_of_pieces = torrent.get_no_of_pieces();
+
+        PieceQueue {
+            torrent,
+            state: Mutex::new(QueueState {
+                pending: pieces.into_iter().collect(),
+                downloaders: vec![0; no_of_pieces],
+                downloads: HashMap::new(),
+                completed: vec![false; no_of_pieces],
+                availability: vec![0; no_of_pieces],
+                completed_any: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Takes a pending piece the peer has: the rarest one among the
+    /// connected peers, or any of them for the first piece so that we soon
+    /// have something to share. Ties are broken at random so that peers do
+    /// not all go for the same pieces.
+    ///
+    /// Once every piece left is being downloaded (endgame mode), the peer
+    /// joins the download of one of them so that the last blocks do not
+    /// wait for the slowest peers: the one with the fewest downloaders.
+    fn take(&self, bitfield: &Bitfield) -> Option<Arc<PieceDownload>> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.is_empty() {
+            let download = state
+                .downloads
+                .values()
+                .filter(|download| bitfield.has(download.index) && !download.is_complete())
+                .min_by_key(|download| state.downloaders[download.index])?
+                .clone();
+            state.downloaders[download.index] += 1;
+
+            return Some(download);
+        }
+
+        let candidates: Vec<usize> = (0..state.pending.len())
+            .filter(|i| bitfield.has(state.pending[*i]))
+            .collect();
+
+        let candidates = if state.completed_any {
+            let rarity = |i: &usize| state.availability[state.pending[*i]];
+            let rarest = candidates.iter().map(rarity).min()?;
+            candidates
+                .into_iter()
+                .filter(|i| rarity(i) == rarest)
+                .collect()
+        } else {
+            candidates
+        };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let position = candidates[(random_u64() % candidates.len() as u64) as usize];
+        let piece_index = state.pending.swap_remove(position);
+        state.downloaders[piece_index] += 1;
+
+        let download = Arc::new(PieceDownload::new(
+            piece_index,
+            self.torrent.get_piece_length(piece_index),
+        ));
+        state.downloads.insert(piece_index, download.clone());
+
+        Some(download)
+    }
+
+    /// Gives a piece back after the peer downloading it failed or another
+    /// peer completed it first. Once no peer is downloading it anymore, it
+    /// is pending again unless it was completed, starting over from the
+    /// first block.
+    fn put_back(&self, piece_index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.downloaders[piece_index] -= 1;
+        if state.downloaders[piece_index] > 0 {
+            return;
+        }
+
+        state.downloads.remove(&piece_index);
+        if !state.completed[piece_index] {
+            state.pending.push(piece_index);
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Marks a piece as downloaded.
+    fn complete(&self, piece_index: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.downloaders[piece_index] -= 1;
+        if state.downloaders[piece_index] == 0 {
+            state.downloads.remove(&piece_index);
+        }
+        state.completed[piece_index] = true;
+        state.completed_any = true;
+    }
+
+    /// Counts pieces newly announced by a peer.
+    fn add_availability(&self, pieces: &[usize]) {
+        if pieces.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        for piece_index in pieces {
+            state.availability[*piece_index] += 1;
+        }
+    }
+
+    /// Stops counting the pieces of a peer which disconnected.
+    fn remove_availability(&self, counted: &Bitfield) {
+        let mut state = self.state.lock().unwrap();
+
+        for piece_index in 0..state.availability.len() {
+            if counted.has(piece_index) {
+                state.availability[piece_index] -= 1;
+            }
+        }
+    }
+}
+
+/// Downloads the given pieces from as many of the peers as possible at once,
+/// handing every verified piece to `on_piece` as soon as it completes. More
+/// peers can be sent over `peers` while the download is running. When no
+/// peer is left to download from, the download waits at least
+/// [`RETRY_INTERVAL`] for more peers, until `peers` is closed and then fails.
+pub async fn download_pieces(
+    torrent: Arc<TorrentFile>,
+    mut peers: mpsc::UnboundedReceiver<Vec<Peer>>,
+    piece_indices: Vec<usize>,
+    options: DownloadOptions,
+    mut on_piece: impl FnMut(DownloadedPiece) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let queue = Arc::new(PieceQueue::new(torrent.clone(), piece_indices.clone()));
+    // bounded so that pieces waiting to be stored cannot pile up in memory
+    let (tx, mut rx) = mpsc::channel::<DownloadedPiece>(MAX_PEER_CONNECTIONS);
+
+    let mut workers = JoinSet::new();
+    let mut connected: Vec<Peer> = vec![];
+    let mut more_peers = true;
+
+    let mut remaining = piece_indices.len();
+    while remaining > 0 {
+        tokio::select! {
+            new_peers = peers.recv(), if more_peers => {
+                match new_peers {
+                    Some(new_peers) => connect_peers(
+                        new_peers,
+                        &mut connected,
+                        &mut workers,
+                        &torrent,
+                        &queue,
+                        &tx,
+                        &options,
+                    ),
+                    None => more_peers = false,
+                }
+
+                if workers.is_empty() && !more_peers {
+                    anyhow::bail!("No peers to download {} pieces from", remaining);
+                }
+            }
+            Some(piece) = rx.recv() => {
+                on_piece(piece)?;
+                remaining -= 1;
+            }
+            Some(finished) = workers.join_next() => {
+                if let Ok(peer) = finished {
+                    connected.retain(|p| !p.has_address_of(&peer));
+                }
+
+                if workers.is_empty() {
+                    while let Ok(piece) = rx.try_recv() {
+                        on_piece(piece)?;
+                        remaining -= 1;
+                    }
+                    if remaining > 0 && !more_peers {
+                        anyhow::bail!("All peers failed with {} pieces left", remaining);
+                    }
+                    if remaining > 0 {
+                        eprintln!(
+                            "All peers failed with {} pieces left, waiting for more peers",
+                            remaining
+                        );
+                        tokio::time::sleep(RETRY_INTERVAL).await;
+                    }
+                }
+            }
+        }
+    }
+    workers.abort_all();
+
+    Ok(())
+}
+
+/// Starts a worker for each of the peers not connected yet, as long as there
+/// are free connection slots.
+fn connect_peers(
+    new_peers: Vec<Peer>,
+    connected: &mut Vec<Peer>,
+    workers: &mut JoinSet<Peer>,
+    torrent: &Arc<TorrentFile>,
+    queue: &Arc<PieceQueue>,
+    tx: &mpsc::Sender<DownloadedPiece>,
+    options: &DownloadOptions,
+) {
+    for peer in new_peers {
+        if connected.len() >= MAX_PEER_CONNECTIONS
+            || connected.iter().any(|p| p.has_address_of(&peer))
+        {
+            continue;
+        }
+
+        let torrent = torrent.clone();
+        let queue = queue.clone();
+        let tx = tx.clone();
+        let options = options.clone();
+
+        connected.push(peer.clone());
+        workers.spawn(async move {
+            if let Err(err) = peer_worker(&peer, &torrent, &queue, tx, &options).await {
+                eprintln!("Peer {} failed: {}", peer, err);
+            }
+            peer
+        });
+    }
+}
+
+/// Connects to a peer and downloads pieces from the queue over the same
+/// session until the download is over, a piece that could not be completed
+/// goes back to the queue.
+async fn peer_worker(
+    peer: &Peer,
+    torrent: &TorrentFile,
+    queue: &PieceQueue,
+    tx: mpsc::Sender<DownloadedPiece>,
+    options: &DownloadOptions,
+) -> anyhow::Result<()> {
+    let mut session = PeerSession::connect_to_peer(
+        peer,
+        torrent.info_hash(),
+        "00112233445566778899".to_string(),
+    )
+    .await?;
+    session.pipeline_depth = options.pipeline_depth;
+    session.set_no_of_pieces(torrent.get_no_of_pieces());
+
+    let result = download_from_peer(&mut session, torrent, queue, tx).await;
+    // the pieces announced last may not be counted yet
+    queue.add_availability(&session.take_new_pieces());
+    queue.remove_availability(&session.bitfield);
+
+    result
+}
+
+/// Downloads pieces from a peer. The pieces of the peer are counted as soon
+/// as it announces them, also while it is choking us, failing once it kept
+/// us choked for [`UNCHOKE_TIMEOUT`].
+async fn download_from_peer(
+    session: &mut PeerSession,
+    torrent: &TorrentFile,
+    queue: &PieceQueue,
+    tx: mpsc::Sender<DownloadedPiece>,
+) -> anyhow::Result<()> {
+    session
+        .send(&PeerMessage::from_empty_payload(MessageType::Interested))
+        .await?;
+    let mut unchoked_at = Instant::now();
+
+    loop {
+        // the bitfield grows with the `Have` messages read along the way
+        queue.add_availability(&session.take_new_pieces());
+
+        if !session.peer_choking {
+            unchoked_at = Instant::now();
+        } else if unchoked_at.elapsed() >= UNCHOKE_TIMEOUT {
+            anyhow::bail!(
+                "Peer {} did not unchoke us within {:?}",
+                session.addr,
+                UNCHOKE_TIMEOUT
+            );
+        }
+
+        let piece = if session.peer_choking {
+            None
+        } else {
+            queue.take(&session.bitfield)
+        };
+        let piece = match piece {
+            Some(piece) => piece,
+            None => {
+                // keep up with the peer's messages while waiting for pieces
+                // or to be unchoked
+                let wake = tokio::time::timeout(QUEUE_POLL_INTERVAL, queue.notify.notified());
+                session
+                    .read_message_until(async {
+                        let _ = wake.await;
+                    })
+                    .await?;
+                continue;
+            }
+        };
+
+        let piece_index = piece.index;
+        let data = match session.download_piece(&piece).await {
+            Ok(Some(data)) => data,
+            Ok(None) => {
+                // the other peers sent the last blocks in endgame mode
+                queue.put_back(piece_index);
+                continue;
+            }
+            Err(err) => {
+                queue.put_back(piece_index);
+                return Err(err);
+            }
+        };
+
+        if hex_sha1(&data) != torrent.get_piece_hash(piece_index) {
+            queue.put_back(piece_index);
+            anyhow::bail!("Piece {} does not match its hash", piece_index);
+        }
+        queue.complete(piece_index);
+
+        let piece = DownloadedPiece {
+            index: piece_index,
+            data,
+            peer_id: session.handshake.peer_id,
+        };
+        if tx.send(piece).await.is_err() {
+            // the download is over
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::*;
+    use crate::torrent::TorrentFileInfo;
+
+    /// A queue of all the pieces of a torrent with pieces of 16 bytes, the
+    /// connected peers having `available` pieces between them.
+    fn queue(no_of_pieces: usize, available: &[usize]) -> PieceQueue {
+        let info = TorrentFileInfo {
+            name: "sample".to_string(),
+            piece_length: 16,
+            length: Some(16 * no_of_pieces),
+            files: None,
+            pieces: vec![0; 20 * no_of_pieces],
+        };
+        let torrent =
+            TorrentFile::from_info_bytes(&[], serde_bencode::to_bytes(&info).unwrap()).unwrap();
+
+        let queue = PieceQueue::new(Arc::new(torrent), 0..no_of_pieces);
+        queue.add_availability(available);
+
+        queue
+    }
+
+    fn bitfield(no_of_pieces: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(no_of_pieces);
+        pieces
+            .iter()
+            .for_each(|piece_index| bitfield.set(*piece_index));
+
+        bitfield
+    }
+
+    fn take_index(queue: &PieceQueue, bitfield: &Bitfield) -> Option<usize> {
+        queue.take(bitfield).map(|download| download.index)
+    }
+
+    #[test]
+    fn takes_the_rarest_pieces_first() {
+        // availability: 3, 1, 2, 1
+        let queue = queue(4, &[0, 0, 0, 1, 2, 2, 3]);
+        queue.state.lock().unwrap().completed_any = true;
+        let peer = Bitfield::full(4);
+
+        let rarest: BTreeSet<usize> = (0..2).map(|_| take_index(&queue, &peer).unwrap()).collect();
+
+        assert_eq!(rarest, BTreeSet::from([1, 3]));
+        assert_eq!(take_index(&queue, &peer), Some(2));
+        assert_eq!(take_index(&queue, &peer), Some(0));
+    }
+
+    #[test]
+    fn takes_a_random_first_piece_the_peer_has() {
+        let peer = bitfield(4, &[1, 2]);
+
+        let taken: BTreeSet<usize> = (0..64)
+            .map(|_| take_index(&queue(4, &[1, 1, 2, 3]), &peer).unwrap())
+            .collect();
+
+        // piece 0 is the rarest, but the peer does not have it
+        assert_eq!(taken, BTreeSet::from([1, 2]));
+    }
+
+    #[test]
+    fn takes_only_pieces_the_peer_has() {
+        let queue = queue(4, &[0, 1, 2, 2, 3, 3]);
+        queue.state.lock().unwrap().completed_any = true;
+        let peer = bitfield(4, &[2, 3]);
+
+        let taken: BTreeSet<usize> = (0..2).map(|_| take_index(&queue, &peer).unwrap()).collect();
+
+        assert_eq!(taken, BTreeSet::from([2, 3]));
+        assert_eq!(take_index(&queue, &peer), None);
+        assert_eq!(take_index(&queue, &bitfield(4, &[0])), Some(0));
+    }
+
+    #[test]
+    fn joins_the_download_with_the_fewest_downloaders_in_endgame() {
+        let queue = queue(2, &[0, 1]);
+        let peer = Bitfield::full(2);
+        let first = take_index(&queue, &peer).unwrap();
+        let second = take_index(&queue, &peer).unwrap();
+
+        assert_eq!(take_index(&queue, &bitfield(2, &[first])), Some(first));
+        assert_eq!(take_index(&queue, &peer), Some(second));
+        assert_eq!(queue.state.lock().unwrap().downloaders, vec![2, 2]);
+    }
+}
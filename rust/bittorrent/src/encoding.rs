@@ -0,0 +1,185 @@
+use thiserror::Error;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("invalid character {character:?} at {position}")]
+pub struct InvalidCharacterError {
+    pub position: usize,
+    pub character: char,
+}
+
+/// Encodes bytes using the standard base64 alphabet with padding.
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+
+    out
+}
+
+/// Decodes standard base64, the trailing padding is optional.
+pub fn base64_decode(encoded: &str) -> Result<Vec<u8>, InvalidCharacterError> {
+    let trimmed = encoded.trim_end_matches('=');
+    let mut out = Vec::with_capacity(trimmed.len() * 3 / 4);
+
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for (position, c) in trimmed.char_indices() {
+        let value = match BASE64_ALPHABET.iter().position(|a| *a as char == c) {
+            Some(value) => value as u32,
+            None => {
+                return Err(InvalidCharacterError {
+                    position,
+                    character: c,
+                })
+            }
+        };
+
+        acc = acc << 6 | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(out)
+}
+
+/// Decodes RFC 4648 base32 (as used by magnet links), case-insensitive and
+/// with optional padding.
+pub fn base32_decode(encoded: &str) -> Result<Vec<u8>, InvalidCharacterError> {
+    let trimmed = encoded.trim_end_matches('=');
+    let mut out = Vec::with_capacity(trimmed.len() * 5 / 8);
+
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for (position, c) in trimmed.char_indices() {
+        let upper = c.to_ascii_uppercase();
+        let value = match BASE32_ALPHABET.iter().position(|a| *a as char == upper) {
+            Some(value) => value as u32,
+            None => {
+                return Err(InvalidCharacterError {
+                    position,
+                    character: c,
+                })
+            }
+        };
+
+        acc = acc << 5 | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // test vectors from RFC 4648
+    const BASE64_VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ];
+
+    const BASE32_VECTORS: [(&str, &str); 7] = [
+        ("", ""),
+        ("f", "MY======"),
+        ("fo", "MZXQ===="),
+        ("foo", "MZXW6==="),
+        ("foob", "MZXW6YQ="),
+        ("fooba", "MZXW6YTB"),
+        ("foobar", "MZXW6YTBOI======"),
+    ];
+
+    #[test]
+    fn base64_encode_vectors() {
+        for (decoded, encoded) in BASE64_VECTORS {
+            assert_eq!(base64_encode(decoded.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn base64_decode_vectors() {
+        for (decoded, encoded) in BASE64_VECTORS {
+            assert_eq!(base64_decode(encoded), Ok(decoded.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn base64_padding_is_optional() {
+        assert_eq!(base64_decode("Zm9vYg"), Ok(b"foob".to_vec()));
+    }
+
+    #[test]
+    fn base64_round_trips_binary() {
+        let bytes: Vec<u8> = (0..=255).collect();
+
+        assert_eq!(base64_decode(&base64_encode(&bytes)), Ok(bytes));
+    }
+
+    #[test]
+    fn base64_invalid_character() {
+        assert_eq!(
+            base64_decode("Zm9v-g=="),
+            Err(InvalidCharacterError {
+                position: 4,
+                character: '-'
+            })
+        );
+    }
+
+    #[test]
+    fn base32_decode_vectors() {
+        for (decoded, encoded) in BASE32_VECTORS {
+            assert_eq!(base32_decode(encoded), Ok(decoded.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn base32_is_case_insensitive_and_padding_is_optional() {
+        assert_eq!(base32_decode("mzxw6ytboi"), Ok(b"foobar".to_vec()));
+    }
+
+    #[test]
+    fn base32_invalid_character() {
+        assert_eq!(
+            base32_decode("MZXW1"),
+            Err(InvalidCharacterError {
+                position: 4,
+                character: '1'
+            })
+        );
+    }
+}
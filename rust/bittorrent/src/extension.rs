@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::metadata::{OUR_UT_METADATA_ID, UT_METADATA};
+use crate::peer_message::PeerMessage;
+
+/// Extended message id reserved for the extension handshake itself.
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// Client name and version advertised in the `v` key.
+pub const CLIENT_VERSION: &str = "bittorrent-starter-rust 0.1.0";
+
+/// The payload of the extension protocol handshake (BEP 10).
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct ExtensionHandshake {
+    /// Maps the name of each supported extension to the extended message id
+    /// the sender wants to receive it with, an id of 0 disables the extension.
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+
+    /// Client name and version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub v: Option<String>,
+
+    /// The local TCP listen port of the sender.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p: Option<u16>,
+
+    /// The number of outstanding request messages the sender supports
+    /// without dropping any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<usize>,
+
+    /// The size of the info dictionary in bytes (BEP 9).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    /// The handshake we send, the extensions we support are listed in `m`.
+    pub fn ours() -> ExtensionHandshake {
+        ExtensionHandshake {
+            m: BTreeMap::from([(UT_METADATA.to_string(), OUR_UT_METADATA_ID)]),
+            v: Some(CLIENT_VERSION.to_string()),
+            ..Default::default()
+        }
+    }
+
+    /// The extended message id the peer wants to receive the extension with.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.m.get(name).copied().filter(|id| *id != 0)
+    }
+
+    pub fn to_message(&self) -> PeerMessage {
+        let payload = serde_bencode::to_bytes(self).expect("Could not encode extension handshake");
+
+        PeerMessage::extended(EXTENDED_HANDSHAKE_ID, &payload)
+    }
+
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<ExtensionHandshake> {
+        Ok(serde_bencode::from_bytes(payload)?)
+    }
+}
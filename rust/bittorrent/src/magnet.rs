@@ -0,0 +1,208 @@
+use std::fmt;
+
+use thiserror::Error;
+
+use crate::encoding::base32_decode;
+use crate::torrent::TorrentFile;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTIH_PREFIX: &str = "urn:btih:";
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum MagnetError {
+    #[error("expected the link to start with {MAGNET_PREFIX}")]
+    NotAMagnetLink,
+    #[error("could not parse the query string of the magnet link")]
+    InvalidQuery,
+    #[error("the magnet link has no urn:btih exact topic")]
+    MissingInfoHash,
+    #[error("invalid info hash {0:?}, expected 40 hex or 32 base32 characters")]
+    InvalidInfoHash(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    /// xt: the info hash of the torrent (urn:btih).
+    pub info_hash: [u8; 20],
+
+    /// dn: the display name, purely advisory.
+    pub display_name: Option<String>,
+
+    /// tr: the tracker URLs, in the order they appear in the link.
+    pub trackers: Vec<String>,
+
+    /// x.pe: peers to connect to directly, as `host:port`.
+    pub peers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<MagnetLink, MagnetError> {
+        let query = link
+            .strip_prefix(MAGNET_PREFIX)
+            .ok_or(MagnetError::NotAMagnetLink)?;
+        let params: Vec<(String, String)> =
+            serde_urlencoded::from_str(query).map_err(|_| MagnetError::InvalidQuery)?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = vec![];
+        let mut peers = vec![];
+
+        for (key, value) in params {
+            match key.as_str() {
+                // other exact topics (e.g. urn:btmh for v2 torrents) are not supported
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix(BTIH_PREFIX) {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+
+    pub fn from_torrent(torrent: &TorrentFile) -> MagnetLink {
+        MagnetLink {
+            info_hash: torrent.info_hash(),
+            display_name: Some(torrent.info.name.clone()),
+            trackers: torrent.get_tracker_tiers().into_iter().flatten().collect(),
+            peers: vec![],
+        }
+    }
+}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}xt={}{}",
+            MAGNET_PREFIX,
+            BTIH_PREFIX,
+            hex::encode(self.info_hash)
+        )?;
+
+        let params: Vec<(&str, &str)> = self
+            .display_name
+            .iter()
+            .map(|name| ("dn", name.as_str()))
+            .chain(self.trackers.iter().map(|t| ("tr", t.as_str())))
+            .chain(self.peers.iter().map(|p| ("x.pe", p.as_str())))
+            .collect();
+
+        if !params.is_empty() {
+            let query = serde_urlencoded::to_string(params).map_err(|_| fmt::Error)?;
+            write!(f, "&{}", query)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => base32_decode(hash).ok(),
+        _ => None,
+    };
+
+    bytes
+        .and_then(|b| b.try_into().ok())
+        .ok_or_else(|| MagnetError::InvalidInfoHash(hash.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX_INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+    const BASE32_INFO_HASH: &str = "22PZDZVSVZGFIJDI2EDTU4OU5IJYPGT7";
+
+    fn info_hash() -> [u8; 20] {
+        hex::decode(HEX_INFO_HASH).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn parses_a_hex_info_hash() {
+        let link = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", HEX_INFO_HASH)).unwrap();
+
+        assert_eq!(link.info_hash, info_hash());
+        assert_eq!(link.display_name, None);
+        assert!(link.trackers.is_empty());
+    }
+
+    #[test]
+    fn parses_a_base32_info_hash() {
+        let link = MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", BASE32_INFO_HASH)).unwrap();
+
+        assert_eq!(link.info_hash, info_hash());
+    }
+
+    #[test]
+    fn keeps_every_tracker_in_order() {
+        let link = MagnetLink::parse(&format!(
+            "magnet:?tr=http%3A%2F%2Fa.example%2Fannounce&xt=urn:btih:{}\
+             &tr=udp%3A%2F%2Fb.example%3A6969%2Fannounce",
+            HEX_INFO_HASH
+        ))
+        .unwrap();
+
+        assert_eq!(
+            link.trackers,
+            vec![
+                "http://a.example/announce".to_string(),
+                "udp://b.example:6969/announce".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_the_display_name() {
+        let link = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{}&dn=sample%20file%2B1.txt",
+            HEX_INFO_HASH
+        ))
+        .unwrap();
+
+        assert_eq!(link.display_name.as_deref(), Some("sample file+1.txt"));
+    }
+
+    #[test]
+    fn requires_a_btih_exact_topic() {
+        assert_eq!(
+            MagnetLink::parse("magnet:?dn=sample"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xt=urn:btmh:1220abcd"),
+            Err(MagnetError::MissingInfoHash)
+        );
+    }
+
+    #[test]
+    fn rejects_an_invalid_info_hash() {
+        for hash in ["d69f91e6", "z69f91e6b2ae4c542468d1073a71d4ea13879a7f"] {
+            assert_eq!(
+                MagnetLink::parse(&format!("magnet:?xt=urn:btih:{}", hash)),
+                Err(MagnetError::InvalidInfoHash(hash.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_links() {
+        assert_eq!(
+            MagnetLink::parse("http://example.com/?xt=urn:btih:abc"),
+            Err(MagnetError::NotAMagnetLink)
+        );
+    }
+}
@@ -1,121 +1,329 @@
+mod announce_list;
+mod announcer;
 mod bencode;
+mod bitfield;
+mod choker;
 mod cmd_args;
+mod download;
+mod encoding;
+mod extension;
 mod hash;
+mod magnet;
+mod metadata;
 mod peer_message;
+mod peer_session;
+mod piece_download;
+mod random;
+mod seed;
+mod storage;
 mod torrent;
 mod trackers;
+mod udp_tracker;
+mod verify;
 
+use std::convert::Infallible;
 use std::fs;
-use std::mem::size_of;
+use std::io::Write;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
+use announcer::{Announcer, TransferStats};
+use bitfield::Bitfield;
 use clap::Parser;
 use cmd_args::{Args, Command};
-use hash::b_sha1;
-use peer_message::MessageType;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
+use download::{download_pieces, DownloadOptions};
+use magnet::MagnetLink;
+use metadata::fetch_metadata_from_any;
+use peer_session::PeerSession;
+use seed::{listen, SeededTorrent, LISTEN_PORT};
+use storage::{FsyncPolicy, Storage};
+use tokio::sync::mpsc;
 use torrent::TorrentFile;
-use trackers::PeerHandshake;
-
-use crate::peer_message::PeerMessage;
-use crate::trackers::{DiscoverPeersRequest, DiscoverPeersResponse};
-use crate::{bencode::decode_bencoded_value, hash::hex_sha1};
-
-const MAX_BLOCK_SIZE: usize = 1 << 14;
-
-async fn get_peers(torrent: &TorrentFile) -> anyhow::Result<Vec<SocketAddr>> {
-    let encoded_info = serde_bencode::to_bytes(&torrent.info).unwrap();
-
-    let req = DiscoverPeersRequest {
-        announce_url: torrent.announce.to_string(),
-        info_hash: b_sha1(&encoded_info).try_into().unwrap(),
-        peer_id: "00112233445566778899".to_string(),
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: torrent.info.length,
-        compact: 1,
-    };
+use verify::{verify, FileStatus, PieceStatus};
 
-    let bytes = reqwest::get(&req.get_url()).await?.bytes().await?;
-    let peers_response = DiscoverPeersResponse::from_bencoded_bytes(&bytes[..]).unwrap();
+use crate::bencode::{decode_bencoded_value, BencodeValue, DecodeMode};
+use crate::trackers::{get_scrape_url, AnnounceEvent, Peer, ScrapeRequest, ScrapeResponse};
 
-    Ok(peers_response.parse_peers())
-}
+/// Reported as `left` while the size of a magnet link's torrent is unknown.
+const UNKNOWN_LEFT: usize = 1;
 
-async fn download_piece(torrent: &TorrentFile, piece_index: usize) -> anyhow::Result<Vec<u8>> {
-    let peers = get_peers(&torrent).await?;
+/// How long the `handshake` command waits for the peer's extension handshake.
+const EXTENSION_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
-    let encoded_info = serde_bencode::to_bytes(&torrent.info).unwrap();
-    let info_hash = b_sha1(&encoded_info);
+async fn get_peers(torrent: &TorrentFile) -> anyhow::Result<Vec<Peer>> {
+    get_peers_from_trackers(
+        torrent.get_tracker_tiers(),
+        torrent.info_hash(),
+        torrent.get_total_length(),
+    )
+    .await
+}
 
-    let handshake = PeerHandshake::from(
-        info_hash.try_into().unwrap(),
-        "00112233445566778899".to_string(),
-    );
+async fn get_peers_from_trackers(
+    tiers: Vec<Vec<String>>,
+    info_hash: [u8; 20],
+    left: usize,
+) -> anyhow::Result<Vec<Peer>> {
+    let stats = Arc::new(TransferStats::new(left));
 
-    let mut stream = TcpStream::connect(peers.first().unwrap()).await?;
+    Announcer::new(tiers, info_hash, stats).announce(None).await
+}
 
-    handshake.write_to_stream(&mut stream).await?;
+/// Gets the info dictionary of a magnet link from the peers given in the
+/// link or returned by its trackers.
+async fn get_magnet_torrent(magnet: &MagnetLink) -> anyhow::Result<TorrentFile> {
+    let mut peers: Vec<Peer> = vec![];
+    for peer in &magnet.peers {
+        match tokio::net::lookup_host(peer).await {
+            Ok(addrs) => peers.extend(addrs.map(Peer::from)),
+            Err(err) => eprintln!("Could not resolve peer {}: {}", peer, err),
+        }
+    }
+    if !magnet.trackers.is_empty() {
+        // Magnet links have no tiers, each tracker is asked for peers.
+        let tiers = magnet.trackers.iter().map(|t| vec![t.clone()]).collect();
+        match get_peers_from_trackers(tiers, magnet.info_hash, UNKNOWN_LEFT).await {
+            Ok(tracker_peers) => peers.extend(tracker_peers),
+            Err(err) => eprintln!("Could not get peers from the trackers: {}", err),
+        }
+    }
 
-    let mut buf = [0; size_of::<PeerHandshake>()];
-    stream.read_exact(&mut buf).await?;
+    let info_bytes =
+        fetch_metadata_from_any(&peers, magnet.info_hash, "00112233445566778899".to_string())
+            .await?;
 
-    let handshake_response: PeerHandshake = buf.try_into().unwrap();
+    TorrentFile::from_info_bytes(&magnet.trackers, info_bytes)
+}
 
-    println!("Peer ID: {}", hex::encode(handshake_response.peer_id));
+fn print_info(torrent: &TorrentFile) {
+    if let Some(announce) = &torrent.announce {
+        println!("Tracker URL: {}", announce);
+    }
+    if let Some(tiers) = &torrent.announce_list {
+        tiers
+            .iter()
+            .enumerate()
+            .for_each(|(i, tier)| println!("Tracker Tier {}: {}", i, tier.join(" ")));
+    }
+    println!("Length: {}", torrent.get_total_length());
 
-    let bitfield = PeerMessage::read(&mut stream).await?;
-    assert_eq!(bitfield.id, MessageType::Bitfield);
+    println!("Info Hash: {}", hex::encode(torrent.info_hash()));
 
-    PeerMessage::from_empty_payload(MessageType::Interested)
-        .write(&mut stream)
-        .await?;
+    println!("Piece Length: {}", torrent.info.piece_length);
+    println!("Piece Hashes: ");
+    torrent.info.pieces.chunks_exact(20).for_each(|ch| {
+        println!("{}", hex::encode(ch));
+    });
 
-    let unchoke = PeerMessage::read(&mut stream).await?;
-    assert_eq!(unchoke.id, MessageType::Unchoke);
+    if torrent.is_multi_file() {
+        println!("Files: ");
+        torrent.get_file_layout().iter().for_each(|file| {
+            println!("{} {}", file.length, file.path.display());
+        });
+    }
+}
 
-    let piece_length = torrent.info.piece_length;
+/// Scrapes each tracker of the torrents once, for all the torrents it serves,
+/// and prints the stats by torrent.
+async fn scrape(torrents: &[TorrentFile]) -> anyhow::Result<()> {
+    let mut requests: Vec<ScrapeRequest> = vec![];
+    for torrent in torrents {
+        for tracker in torrent.get_tracker_tiers().into_iter().flatten() {
+            let scrape_url = match get_scrape_url(&tracker) {
+                Some(scrape_url) => scrape_url,
+                None => {
+                    eprintln!("Tracker {} does not support scraping", tracker);
+                    continue;
+                }
+            };
+
+            match requests.iter_mut().find(|r| r.scrape_url == scrape_url) {
+                Some(request) if request.info_hashes.contains(&torrent.info_hash()) => {}
+                Some(request) => request.info_hashes.push(torrent.info_hash()),
+                None => requests.push(ScrapeRequest {
+                    scrape_url,
+                    info_hashes: vec![torrent.info_hash()],
+                }),
+            }
+        }
+    }
 
-    let start_index = piece_length * piece_index;
-    let end_index = std::cmp::min(start_index + piece_length, torrent.info.length);
+    let mut responses: Vec<(&ScrapeRequest, ScrapeResponse)> = vec![];
+    for request in &requests {
+        match request.send().await {
+            Ok(response) => responses.push((request, response)),
+            Err(err) => eprintln!("Could not scrape {}: {}", request.scrape_url, err),
+        }
+    }
+    if responses.is_empty() {
+        anyhow::bail!("Could not scrape any tracker");
+    }
+
+    for torrent in torrents {
+        let info_hash = torrent.info_hash();
+        println!("Info Hash: {}", hex::encode(info_hash));
+        println!("Name: {}", torrent.info.name);
 
-    let mut data: Vec<u8> = vec![];
-    let mut cur_index = start_index;
-    while cur_index < end_index {
-        let block_length = std::cmp::min(MAX_BLOCK_SIZE, end_index - cur_index);
+        for (request, response) in &responses {
+            if !request.info_hashes.contains(&info_hash) {
+                continue;
+            }
 
-        PeerMessage::interested(
-            piece_index as u32,
-            (cur_index - start_index) as u32,
-            block_length as u32,
-        )
-        .write(&mut stream)
-        .await?;
+            println!("Tracker URL: {}", request.scrape_url);
+            match response.get_stats(&info_hash) {
+                Some(stats) => {
+                    println!("Seeders: {}", stats.complete);
+                    println!("Leechers: {}", stats.incomplete);
+                    println!("Downloads: {}", stats.downloaded);
+                }
+                None => println!("Unknown to the tracker"),
+            }
+        }
+    }
 
-        let piece = PeerMessage::read(&mut stream).await?;
-        assert_eq!(piece.id, MessageType::Piece);
+    Ok(())
+}
 
-        let _res_index = i32::from_be_bytes(
-            piece.payload[..4]
-                .try_into()
-                .expect("Could not read result index"),
+async fn download(
+    torrent: TorrentFile,
+    output: &Path,
+    options: DownloadOptions,
+    fsync: FsyncPolicy,
+    upload_slots: usize,
+) -> anyhow::Result<()> {
+    let torrent = Arc::new(torrent);
+    let mut storage = Storage::open(&torrent, output, fsync)?;
+
+    let missing = storage.missing_pieces(&torrent)?;
+    let reused = torrent.get_no_of_pieces() - missing.len();
+    if reused > 0 {
+        println!(
+            "Reusing {} of {} pieces already on disk.",
+            reused,
+            torrent.get_no_of_pieces()
         );
-        let _res_begin = i32::from_be_bytes(
-            piece.payload[4..8]
-                .try_into()
-                .expect("Could not read result begin"),
+    }
+
+    if missing.is_empty() {
+        return storage.finish();
+    }
+
+    let left = missing.iter().map(|i| torrent.get_piece_length(*i)).sum();
+    let stats = Arc::new(TransferStats::new(left));
+    let mut have = Bitfield::full(torrent.get_no_of_pieces());
+    missing
+        .iter()
+        .for_each(|piece_index| have.clear(*piece_index));
+    let seeded = Arc::new(SeededTorrent::new(
+        torrent.clone(),
+        storage,
+        have,
+        stats.clone(),
+        upload_slots,
+    ));
+    let mut announcer = Announcer::new(
+        torrent.get_tracker_tiers(),
+        torrent.info_hash(),
+        stats.clone(),
+    );
+
+    let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+    peers_tx.send(announcer.announce(Some(AnnounceEvent::Started)).await?)?;
+
+    let downloaded = tokio::select! {
+        result = download_pieces(
+            torrent.clone(),
+            peers_rx,
+            missing,
+            options,
+            |piece| {
+                seeded.write_piece(piece.index, &piece.data)?;
+                seeded.choker.add_downloaded(piece.peer_id, piece.data.len());
+                stats.add_downloaded(piece.data.len());
+                Ok(())
+            },
+        ) => result,
+        never = upload_while_downloading(seeded.clone(), &stats) => match never {},
+        never = announcer.keep_announcing(peers_tx) => match never {},
+        _ = tokio::signal::ctrl_c() => Err(anyhow::anyhow!("Interrupted")),
+    };
+
+    let finished = downloaded.and_then(|_| seeded.finish());
+    if finished.is_ok() {
+        if let Err(err) = announcer.announce(Some(AnnounceEvent::Completed)).await {
+            eprintln!("Could not announce the completion: {}", err);
+        }
+    }
+    if let Err(err) = announcer.announce(Some(AnnounceEvent::Stopped)).await {
+        eprintln!("Could not announce the stop: {}", err);
+    }
+
+    finished
+}
+
+/// Uploads the pieces we have to the peers connecting on the port announced
+/// to the trackers. The download goes on without uploading if the port
+/// cannot be listened on.
+async fn upload_while_downloading(seeded: Arc<SeededTorrent>, stats: &TransferStats) -> Infallible {
+    let accepting = async {
+        if let Err(err) = listen(LISTEN_PORT, vec![seeded.clone()]).await {
+            eprintln!("Not accepting peers on port {}: {}", LISTEN_PORT, err);
+        }
+        std::future::pending().await
+    };
+
+    tokio::select! {
+        never = accepting => never,
+        never = seeded.choker.run(stats) => never,
+    }
+}
+
+/// Uploads a torrent found complete on disk until interrupted.
+async fn seed(torrent: TorrentFile, path: &Path, upload_slots: usize) -> anyhow::Result<()> {
+    if !verify(&torrent, path).is_ok() {
+        anyhow::bail!(
+            "{} is not a complete download of the torrent, see the verify command",
+            path.display()
         );
+    }
 
-        data.extend_from_slice(&piece.payload[8..]);
-        cur_index += block_length;
+    let torrent = Arc::new(torrent);
+    let storage = Storage::open(&torrent, path, FsyncPolicy::Never)?;
+    let stats = Arc::new(TransferStats::new(0));
+    let seeded = Arc::new(SeededTorrent::new(
+        torrent.clone(),
+        storage,
+        Bitfield::full(torrent.get_no_of_pieces()),
+        stats.clone(),
+        upload_slots,
+    ));
+
+    let mut announcer = Announcer::new(
+        torrent.get_tracker_tiers(),
+        torrent.info_hash(),
+        stats.clone(),
+    );
+    if let Err(err) = announcer.announce(Some(AnnounceEvent::Started)).await {
+        eprintln!("Could not announce the start: {}", err);
     }
+    // we do not connect to anyone, the peers the trackers return are dropped
+    let (peers_tx, _) = mpsc::unbounded_channel();
+
+    println!("Seeding {} on port {}.", torrent.info.name, LISTEN_PORT);
+    let result = tokio::select! {
+        result = listen(LISTEN_PORT, vec![seeded.clone()]) => result,
+        never = seeded.choker.run(&stats) => match never {},
+        never = announcer.keep_announcing(peers_tx) => match never {},
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
 
-    let hash = hex_sha1(&data);
-    assert_eq!(hash, torrent.get_piece_hash(piece_index));
+    if let Err(err) = announcer.announce(Some(AnnounceEvent::Stopped)).await {
+        eprintln!("Could not announce the stop: {}", err);
+    }
 
-    Ok(data)
+    result
 }
 
 #[tokio::main]
@@ -123,71 +331,134 @@ async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
     match args.command {
-        Command::Decode { value } => {
-            let decoded_value = decode_bencoded_value(&value);
-            println!("{}", decoded_value.to_string());
+        Command::Decode {
+            value,
+            file,
+            strict,
+            bytes,
+            pretty,
+        } => {
+            let input = match (value, file) {
+                (_, Some(file)) => fs::read(file)?,
+                (Some(value), None) => value.into_bytes(),
+                (None, None) => unreachable!("clap requires a value or a file"),
+            };
+            let mode = if strict {
+                DecodeMode::Strict
+            } else {
+                DecodeMode::Lenient
+            };
+            let decoded_value = decode_bencoded_value(&input, mode)?;
+
+            if pretty {
+                print!("{}", decoded_value.to_tree(bytes));
+            } else {
+                println!("{}", decoded_value.to_json(bytes));
+            }
+        }
+
+        Command::Encode { value, output } => {
+            let json: serde_json::Value = serde_json::from_str(&value)?;
+            let encoded = BencodeValue::from_json(&json)?.encode();
+
+            match output {
+                Some(output) => fs::write(output, encoded)?,
+                None => std::io::stdout().write_all(&encoded)?,
+            }
         }
 
         Command::Info { filename } => {
             let contents = fs::read(filename).expect("Could not read the torrent file");
-            let torrent = TorrentFile::from_u8_vec(contents);
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
+            let torrent = TorrentFile::from_u8_vec(contents)?;
 
-            let encoded_info = serde_bencode::to_bytes(&torrent.info)
-                .expect("Could not encode torrent info to bytes");
-            println!("Info Hash: {}", hex_sha1(&encoded_info));
-
-            println!("Piece Length: {}", torrent.info.piece_length);
-            println!("Piece Hashes: ");
-            torrent.info.pieces.chunks_exact(20).for_each(|ch| {
-                println!("{}", hex::encode(ch));
-            })
+            print_info(&torrent);
         }
 
         Command::Peers { filename } => {
             let contents = fs::read(&filename).expect("Could not read the torrent file");
 
-            get_peers(&TorrentFile::from_u8_vec(contents))
+            get_peers(&TorrentFile::from_u8_vec(contents)?)
                 .await?
                 .iter()
-                .for_each(|sock| println!("{}", sock.to_string()));
+                .for_each(|sock| println!("{}", sock));
         }
 
         Command::Handshake { filename, peer } => {
             let sock: SocketAddr = peer.parse().expect("Could not parse peer addr");
             let contents = fs::read(&filename).expect("Could not read the torrent file");
-            let torrent = TorrentFile::from_u8_vec(contents);
-
-            let encoded_info = serde_bencode::to_bytes(&torrent.info).unwrap();
-            let info_hash = b_sha1(&encoded_info);
+            let torrent = TorrentFile::from_u8_vec(contents)?;
 
-            let handshake = PeerHandshake::from(
-                info_hash.try_into().unwrap(),
+            let mut session = PeerSession::connect(
+                sock,
+                torrent.info_hash(),
                 "00112233445566778899".to_string(),
-            );
-
-            let mut stream = TcpStream::connect(sock).await?;
-
-            handshake.write_to_stream(&mut stream).await?;
-
-            let mut buf = [0; size_of::<PeerHandshake>()];
-            stream.read_exact(&mut buf).await?;
-
-            let handshake_response: PeerHandshake = buf.try_into().unwrap();
-
-            println!("Peer ID: {}", hex::encode(handshake_response.peer_id));
+            )
+            .await?;
+
+            println!("Peer ID: {}", hex::encode(session.handshake.peer_id));
+
+            if session.supports_extensions() {
+                let waited = tokio::time::timeout(
+                    EXTENSION_HANDSHAKE_TIMEOUT,
+                    session.wait_for_extensions(),
+                )
+                .await;
+
+                match waited {
+                    Ok(extensions) => {
+                        let extensions = extensions?;
+
+                        if let Some(client) = &extensions.v {
+                            println!("Peer Client: {}", client);
+                        }
+                        extensions
+                            .m
+                            .iter()
+                            .for_each(|(name, id)| println!("Peer Extension: {} = {}", name, id));
+                        if let Some(size) = extensions.metadata_size {
+                            println!("Peer Metadata Size: {}", size);
+                        }
+                    }
+                    Err(_) => eprintln!(
+                        "Peer did not send its extension handshake within {:?}",
+                        EXTENSION_HANDSHAKE_TIMEOUT
+                    ),
+                }
+            }
         }
 
         Command::DownloadPiece {
             output,
             torrent,
             piece_index,
+            options,
         } => {
             let contents = fs::read(&torrent).expect("Could not read the torrent file");
-            let torrent = TorrentFile::from_u8_vec(contents);
+            let torrent = Arc::new(TorrentFile::from_u8_vec(contents)?);
+            if piece_index >= torrent.get_no_of_pieces() {
+                anyhow::bail!(
+                    "Piece {} does not exist, the torrent has {} pieces",
+                    piece_index,
+                    torrent.get_no_of_pieces()
+                );
+            }
 
-            let data = download_piece(&torrent, piece_index).await?;
+            let (peers_tx, peers_rx) = mpsc::unbounded_channel();
+            peers_tx.send(get_peers(&torrent).await?)?;
+            drop(peers_tx);
+
+            let mut data = vec![];
+            download_pieces(
+                torrent,
+                peers_rx,
+                vec![piece_index],
+                options.into(),
+                |piece| {
+                    data = piece.data;
+                    Ok(())
+                },
+            )
+            .await?;
 
             fs::write(&output, data).expect("failed to write the data to file");
             println!(
@@ -197,18 +468,133 @@ async fn main() -> anyhow::Result<()> {
             );
         }
 
-        Command::Download { output, torrent } => {
+        Command::Download {
+            output,
+            torrent,
+            options,
+        } => {
             let contents = fs::read(&torrent).expect("Could not read the torrent file");
-            let torrent_file = TorrentFile::from_u8_vec(contents);
-            let mut data: Vec<Vec<u8>> = vec![];
-            for piece_index in 0..torrent_file.get_no_of_pieces() {
-                let piece_data = download_piece(&torrent_file, piece_index).await?;
-                data.push(piece_data);
-            }
+            let torrent_file = TorrentFile::from_u8_vec(contents)?;
 
-            fs::write(&output, data.concat()).expect("failed to write the data to file");
+            let (fsync, upload_slots) = (options.fsync, options.upload_slots);
+            download(torrent_file, &output, options.into(), fsync, upload_slots).await?;
             println!("Downloaded {} to {}.", &torrent, &output.to_str().unwrap());
         }
+
+        Command::MagnetParse { magnet_link } => {
+            let magnet = MagnetLink::parse(&magnet_link)?;
+
+            magnet
+                .trackers
+                .iter()
+                .for_each(|tracker| println!("Tracker URL: {}", tracker));
+            println!("Info Hash: {}", hex::encode(magnet.info_hash));
+            if let Some(name) = &magnet.display_name {
+                println!("Name: {}", name);
+            }
+            magnet
+                .peers
+                .iter()
+                .for_each(|peer| println!("Peer: {}", peer));
+        }
+
+        Command::MagnetInfo { magnet_link } => {
+            let magnet = MagnetLink::parse(&magnet_link)?;
+            let torrent = get_magnet_torrent(&magnet).await?;
+
+            print_info(&torrent);
+        }
+
+        Command::MagnetDownload {
+            output,
+            magnet_link,
+            options,
+        } => {
+            let magnet = MagnetLink::parse(&magnet_link)?;
+            let torrent = get_magnet_torrent(&magnet).await?;
+
+            let (fsync, upload_slots) = (options.fsync, options.upload_slots);
+            download(torrent, &output, options.into(), fsync, upload_slots).await?;
+            println!(
+                "Downloaded {} to {}.",
+                &magnet_link,
+                &output.to_str().unwrap()
+            );
+        }
+
+        Command::Magnet { filename } => {
+            let contents = fs::read(&filename).expect("Could not read the torrent file");
+            let torrent = TorrentFile::from_u8_vec(contents)?;
+
+            println!("{}", MagnetLink::from_torrent(&torrent));
+        }
+
+        Command::Seed {
+            torrent,
+            path,
+            upload_slots,
+        } => {
+            let contents = fs::read(&torrent).expect("Could not read the torrent file");
+            let torrent = TorrentFile::from_u8_vec(contents)?;
+
+            seed(torrent, &path, upload_slots).await?;
+        }
+
+        Command::Scrape { torrents } => {
+            let torrents: Vec<TorrentFile> = torrents
+                .iter()
+                .map(|filename| {
+                    let contents = fs::read(filename).expect("Could not read the torrent file");
+                    TorrentFile::from_u8_vec(contents)
+                })
+                .collect::<anyhow::Result<_>>()?;
+
+            scrape(&torrents).await?;
+        }
+
+        Command::Verify { torrent, path } => {
+            let contents = fs::read(&torrent).expect("Could not read the torrent file");
+            let torrent = TorrentFile::from_u8_vec(contents)?;
+            let report = verify(&torrent, &path);
+
+            for (piece_index, status) in report.pieces.iter().enumerate() {
+                match status {
+                    PieceStatus::Ok => println!("Piece {}: OK", piece_index),
+                    PieceStatus::Mismatch => {
+                        println!("Piece {}: FAILED (hash mismatch)", piece_index)
+                    }
+                    PieceStatus::Unreadable(reason) => {
+                        println!("Piece {}: FAILED ({})", piece_index, reason)
+                    }
+                }
+            }
+            for (layout, status) in &report.files {
+                let path = layout.path.display();
+                match status {
+                    FileStatus::Ok => println!("File {}: OK", path),
+                    FileStatus::Missing => println!("File {}: MISSING", path),
+                    FileStatus::Damaged { bad_pieces, pieces } => println!(
+                        "File {}: FAILED ({} of {} pieces bad)",
+                        path, bad_pieces, pieces
+                    ),
+                }
+            }
+
+            let good_pieces = report
+                .pieces
+                .iter()
+                .filter(|status| **status == PieceStatus::Ok)
+                .count();
+            println!(
+                "Verified {} of {} pieces.",
+                good_pieces,
+                report.pieces.len()
+            );
+
+            if !report.is_ok() {
+                anyhow::bail!("{} does not match the torrent", path.display());
+            }
+        }
     }
 
     Ok(())
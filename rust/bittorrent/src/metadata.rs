@@ -0,0 +1,200 @@
+use std::future::Future;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+
+use crate::bencode::{decode_bencoded_prefix, DecodeMode};
+use crate::hash::b_sha1;
+use crate::peer_message::{MessageType, PeerMessage};
+use crate::peer_session::PeerSession;
+use crate::trackers::Peer;
+
+/// Name of the metadata exchange extension (BEP 9).
+pub const UT_METADATA: &str = "ut_metadata";
+
+/// The extended message id we want to receive ut_metadata messages with.
+pub const OUR_UT_METADATA_ID: u8 = 1;
+
+/// The metadata is transferred in pieces of 16 KiB, the last may be shorter.
+const METADATA_PIECE_SIZE: usize = 1 << 14;
+
+/// Refuse peers advertising absurdly large info dictionaries.
+const MAX_METADATA_SIZE: usize = 1 << 26;
+
+/// How long a peer gets to connect and send its extension handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a peer gets to answer the request for a metadata piece.
+const PIECE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of peers asked for the metadata at the same time.
+const MAX_CONCURRENT_FETCHES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataMessageType {
+    Request = 0,
+    Data = 1,
+    Reject = 2,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct MetadataMessage {
+    /// One of [`MetadataMessageType`].
+    pub msg_type: u8,
+
+    /// The index of the 16 KiB metadata piece.
+    pub piece: usize,
+
+    /// The size of the whole info dictionary, only sent with data messages.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+}
+
+impl MetadataMessage {
+    pub fn request(piece: usize) -> MetadataMessage {
+        MetadataMessage {
+            msg_type: MetadataMessageType::Request as u8,
+            piece,
+            total_size: None,
+        }
+    }
+
+    pub fn to_message(&self, peer_extension_id: u8) -> PeerMessage {
+        let payload = serde_bencode::to_bytes(self).expect("Could not encode metadata message");
+
+        PeerMessage::extended(peer_extension_id, &payload)
+    }
+
+    /// Splits a ut_metadata payload into the message and, for data messages,
+    /// the piece bytes appended after the bencoded dictionary.
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<(MetadataMessage, &[u8])> {
+        let (_, length) = decode_bencoded_prefix(payload, DecodeMode::Lenient)?;
+        let message: MetadataMessage = serde_bencode::from_bytes(&payload[..length])?;
+
+        Ok((message, &payload[length..]))
+    }
+}
+
+/// Downloads the info dictionary from the first of the peers able to
+/// provide it, asking up to [`MAX_CONCURRENT_FETCHES`] of them at a time.
+pub async fn fetch_metadata_from_any(
+    peers: &[Peer],
+    info_hash: [u8; 20],
+    peer_id: String,
+) -> anyhow::Result<Vec<u8>> {
+    let mut peers = peers.iter().cloned();
+    let mut fetches = JoinSet::new();
+
+    loop {
+        while fetches.len() < MAX_CONCURRENT_FETCHES {
+            let Some(peer) = peers.next() else {
+                break;
+            };
+            let peer_id = peer_id.clone();
+            fetches.spawn(async move {
+                let metadata = fetch_metadata(&peer, info_hash, peer_id).await;
+                (peer, metadata)
+            });
+        }
+
+        match fetches.join_next().await {
+            Some(Ok((_, Ok(metadata)))) => return Ok(metadata),
+            Some(Ok((peer, Err(err)))) => {
+                eprintln!("Could not get the metadata from {}: {}", peer, err)
+            }
+            Some(Err(err)) => eprintln!("Metadata fetch failed: {}", err),
+            None => anyhow::bail!("Could not get the metadata from any peer"),
+        }
+    }
+}
+
+/// Downloads the info dictionary from a peer and verifies it against the
+/// info hash.
+pub async fn fetch_metadata(
+    peer: &Peer,
+    info_hash: [u8; 20],
+    peer_id: String,
+) -> anyhow::Result<Vec<u8>> {
+    let mut session = with_timeout(
+        HANDSHAKE_TIMEOUT,
+        PeerSession::connect_to_peer(peer, info_hash, peer_id),
+    )
+    .await?;
+
+    if !session.supports_extensions() {
+        anyhow::bail!("Peer {} does not support the extension protocol", peer);
+    }
+
+    let extensions = with_timeout(HANDSHAKE_TIMEOUT, session.wait_for_extensions()).await?;
+    let peer_extension_id = extensions
+        .extension_id(UT_METADATA)
+        .ok_or_else(|| anyhow::anyhow!("Peer {} does not support {}", peer, UT_METADATA))?;
+    let metadata_size = match extensions.metadata_size {
+        Some(size) if size > 0 && size <= MAX_METADATA_SIZE => size,
+        size => anyhow::bail!(
+            "Peer {} advertised an invalid metadata size {:?}",
+            peer,
+            size
+        ),
+    };
+
+    let no_of_pieces = (metadata_size + METADATA_PIECE_SIZE - 1) / METADATA_PIECE_SIZE;
+    let mut metadata: Vec<u8> = Vec::with_capacity(metadata_size);
+
+    for piece in 0..no_of_pieces {
+        session
+            .send(&MetadataMessage::request(piece).to_message(peer_extension_id))
+            .await?;
+
+        let data = with_timeout(PIECE_TIMEOUT, async {
+            loop {
+                let message = session.read_message().await?;
+                if message.id != MessageType::Extended
+                    || message.payload.first() != Some(&OUR_UT_METADATA_ID)
+                {
+                    continue;
+                }
+
+                let (response, data) = MetadataMessage::from_payload(&message.payload[1..])?;
+                if response.msg_type == MetadataMessageType::Reject as u8 {
+                    anyhow::bail!("Peer {} rejected metadata piece {}", peer, piece);
+                }
+                if response.msg_type == MetadataMessageType::Data as u8 && response.piece == piece {
+                    break Ok(data.to_vec());
+                }
+            }
+        })
+        .await?;
+
+        let expected = std::cmp::min(METADATA_PIECE_SIZE, metadata_size - metadata.len());
+        if data.len() != expected {
+            anyhow::bail!(
+                "Peer {} sent {} bytes for metadata piece {} but expected {}",
+                peer,
+                data.len(),
+                piece,
+                expected
+            );
+        }
+        metadata.extend_from_slice(&data);
+    }
+
+    if b_sha1(&metadata) != info_hash {
+        anyhow::bail!(
+            "Metadata received from {} does not match the info hash",
+            peer
+        );
+    }
+
+    Ok(metadata)
+}
+
+async fn with_timeout<T>(
+    duration: Duration,
+    future: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(duration, future)
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {:?}", duration))?
+}
@@ -1,37 +1,71 @@
-use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream};
+use std::time::Duration;
 
-trait ToBytes {
-    fn to_bytes(&self) -> Vec<u8>;
-}
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Frames larger than this are rejected, it leaves room for blocks of
+/// 128 KiB and the bitfield of a torrent with 2 million pieces.
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 18;
+
+/// Peers drop connections which stay silent for 2 minutes, so a keep-alive
+/// is sent when nothing else was for this long.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum MessageType {
-    Choke = 0,
-    Unchoke = 1,
-    Interested = 2,
-    NotInterested = 3,
-    Have = 4,
-    Bitfield = 5,
-    Request = 6,
-    Piece = 7,
-    Cancel = 8,
+    /// A message without id nor payload which only keeps the connection open.
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have,
+    Bitfield,
+    Request,
+    Piece,
+    Cancel,
+    /// The DHT port of the peer (BEP 5).
+    Port,
+    Extended,
+    /// A message from an extension we do not support, which is ignored.
+    Unknown(u8),
+}
+
+impl From<u8> for MessageType {
+    fn from(id: u8) -> Self {
+        match id {
+            0 => MessageType::Choke,
+            1 => MessageType::Unchoke,
+            2 => MessageType::Interested,
+            3 => MessageType::NotInterested,
+            4 => MessageType::Have,
+            5 => MessageType::Bitfield,
+            6 => MessageType::Request,
+            7 => MessageType::Piece,
+            8 => MessageType::Cancel,
+            9 => MessageType::Port,
+            20 => MessageType::Extended,
+            id => MessageType::Unknown(id),
+        }
+    }
 }
 
-impl TryFrom<u8> for MessageType {
-    type Error = &'static str;
-
-    fn try_from(v: u8) -> Result<Self, Self::Error> {
-        match v {
-            x if x == MessageType::Choke as u8 => Ok(MessageType::Choke),
-            x if x == MessageType::Unchoke as u8 => Ok(MessageType::Unchoke),
-            x if x == MessageType::Interested as u8 => Ok(MessageType::Interested),
-            x if x == MessageType::NotInterested as u8 => Ok(MessageType::NotInterested),
-            x if x == MessageType::Have as u8 => Ok(MessageType::Have),
-            x if x == MessageType::Bitfield as u8 => Ok(MessageType::Bitfield),
-            x if x == MessageType::Request as u8 => Ok(MessageType::Request),
-            x if x == MessageType::Piece as u8 => Ok(MessageType::Piece),
-            x if x == MessageType::Cancel as u8 => Ok(MessageType::Cancel),
-            _ => Err("unkown message type"),
+impl MessageType {
+    /// The id sent on the wire, keep-alives have none.
+    fn to_id(&self) -> Option<u8> {
+        match self {
+            MessageType::KeepAlive => None,
+            MessageType::Choke => Some(0),
+            MessageType::Unchoke => Some(1),
+            MessageType::Interested => Some(2),
+            MessageType::NotInterested => Some(3),
+            MessageType::Have => Some(4),
+            MessageType::Bitfield => Some(5),
+            MessageType::Request => Some(6),
+            MessageType::Piece => Some(7),
+            MessageType::Cancel => Some(8),
+            MessageType::Port => Some(9),
+            MessageType::Extended => Some(20),
+            MessageType::Unknown(id) => Some(*id),
         }
     }
 }
@@ -50,57 +84,215 @@ impl PeerMessage {
         }
     }
 
-    pub fn interested(piece: u32, index: u32, block_length: u32) -> PeerMessage {
+    /// Announces a piece we completed.
+    pub fn have(piece: u32) -> PeerMessage {
+        PeerMessage {
+            id: MessageType::Have,
+            payload: piece.to_be_bytes().to_vec(),
+        }
+    }
+
+    /// Asks for the block of `block_length` bytes at offset `begin` of a piece.
+    pub fn request(piece: u32, begin: u32, block_length: u32) -> PeerMessage {
+        PeerMessage::block(MessageType::Request, piece, begin, block_length)
+    }
+
+    /// Withdraws a `Request` for a block which is not needed anymore.
+    pub fn cancel(piece: u32, begin: u32, block_length: u32) -> PeerMessage {
+        PeerMessage::block(MessageType::Cancel, piece, begin, block_length)
+    }
+
+    fn block(id: MessageType, piece: u32, begin: u32, block_length: u32) -> PeerMessage {
         let mut payload: Vec<u8> = vec![];
         payload.extend_from_slice(&piece.to_be_bytes());
-        payload.extend_from_slice(&index.to_be_bytes());
+        payload.extend_from_slice(&begin.to_be_bytes());
         payload.extend_from_slice(&block_length.to_be_bytes());
 
+        PeerMessage { id, payload }
+    }
+
+    /// A block of a piece, the answer to a `Request`.
+    pub fn piece(piece: u32, begin: u32, block: &[u8]) -> PeerMessage {
+        let mut payload: Vec<u8> = Vec::with_capacity(8 + block.len());
+        payload.extend_from_slice(&piece.to_be_bytes());
+        payload.extend_from_slice(&begin.to_be_bytes());
+        payload.extend_from_slice(block);
+
         PeerMessage {
-            id: MessageType::Request,
+            id: MessageType::Piece,
             payload,
         }
     }
 
-    pub async fn read(stream: &mut TcpStream) -> anyhow::Result<PeerMessage> {
-        let mut length_buf: [u8; 4] = [0; 4];
-        let size = stream.read_exact(&mut length_buf).await?;
-        assert_eq!(size, 4);
-        let length = u32::from_be_bytes(length_buf);
+    /// An extension protocol message (BEP 10), `extended_id` is 0 for the
+    /// extension handshake or the id the receiver assigned to the extension.
+    pub fn extended(extended_id: u8, payload: &[u8]) -> PeerMessage {
+        let mut bytes: Vec<u8> = Vec::with_capacity(1 + payload.len());
+        bytes.push(extended_id);
+        bytes.extend_from_slice(payload);
+
+        PeerMessage {
+            id: MessageType::Extended,
+            payload: bytes,
+        }
+    }
+
+    pub fn keep_alive() -> PeerMessage {
+        PeerMessage::from_empty_payload(MessageType::KeepAlive)
+    }
 
-        let mut buf = vec![0; length as usize];
-        let size = stream.read_exact(&mut buf).await?;
-        assert_eq!(size, length as usize);
+    /// Takes the first complete message out of `buffer`, returning `None`
+    /// while more bytes are needed.
+    pub fn decode(buffer: &mut Vec<u8>) -> anyhow::Result<Option<PeerMessage>> {
+        if buffer.len() < 4 {
+            return Ok(None);
+        }
+
+        let length = u32::from_be_bytes(buffer[..4].try_into().unwrap()) as usize;
+        if length > MAX_MESSAGE_LENGTH {
+            anyhow::bail!(
+                "Message of {} bytes exceeds the limit of {} bytes",
+                length,
+                MAX_MESSAGE_LENGTH
+            );
+        }
+        if buffer.len() < 4 + length {
+            return Ok(None);
+        }
 
-        let id = buf[0].try_into().unwrap();
-        let payload: Vec<u8> = buf[1..].try_into().unwrap();
+        let frame: Vec<u8> = buffer.drain(..4 + length).skip(4).collect();
+        let message = match frame.split_first() {
+            None => PeerMessage::keep_alive(),
+            Some((id, payload)) => PeerMessage {
+                id: MessageType::from(*id),
+                payload: payload.to_vec(),
+            },
+        };
 
-        Ok(PeerMessage { id, payload })
+        Ok(Some(message))
     }
 
-    pub async fn write(&self, stream: &mut TcpStream) -> anyhow::Result<()> {
+    pub fn encode(&self) -> Vec<u8> {
+        let id = match self.id.to_id() {
+            Some(id) => id,
+            None => return vec![0; 4],
+        };
         let len = 1 + self.payload.len() as u32;
 
         let mut bytes: Vec<u8> = Vec::with_capacity(4 + len as usize);
         bytes.extend_from_slice(&len.to_be_bytes());
-        bytes.push(self.id.clone() as u8);
+        bytes.push(id);
         bytes.extend(&self.payload);
 
-        stream.write_all(&bytes).await?;
+        bytes
+    }
+
+    pub async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+        stream.write_all(&self.encode()).await?;
         stream.flush().await?;
 
         Ok(())
     }
 }
 
-impl TryFrom<&[u8]> for PeerMessage {
-    type Error = ();
+/// Reads the messages of a peer from any stream. Partial messages are kept
+/// in a buffer, so a read can be cancelled without losing data.
+pub struct MessageReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> MessageReader<R> {
+    pub fn new(reader: R) -> MessageReader<R> {
+        MessageReader {
+            reader,
+            buffer: vec![],
+        }
+    }
+
+    pub async fn read(&mut self) -> anyhow::Result<PeerMessage> {
+        loop {
+            if let Some(message) = PeerMessage::decode(&mut self.buffer)? {
+                return Ok(message);
+            }
+
+            self.buffer.reserve(1 << 14);
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                anyhow::bail!("The peer closed the connection");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    #[tokio::test]
+    async fn reads_a_keep_alive() {
+        let (mut peer, ours) = duplex(64);
+        let mut reader = MessageReader::new(ours);
+
+        peer.write_all(&[0, 0, 0, 0]).await.unwrap();
+        PeerMessage::have(7).write(&mut peer).await.unwrap();
+
+        assert_eq!(reader.read().await.unwrap().id, MessageType::KeepAlive);
+        let have = reader.read().await.unwrap();
+        assert_eq!(have.id, MessageType::Have);
+        assert_eq!(have.payload, vec![0, 0, 0, 7]);
+    }
+
+    #[tokio::test]
+    async fn reads_an_unknown_message() {
+        let (mut peer, ours) = duplex(64);
+        let mut reader = MessageReader::new(ours);
+
+        peer.write_all(&[0, 0, 0, 3, 42, 1, 2]).await.unwrap();
+
+        let message = reader.read().await.unwrap();
+        assert_eq!(message.id, MessageType::Unknown(42));
+        assert_eq!(message.payload, vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_message_over_the_limit() {
+        let (mut peer, ours) = duplex(64);
+        let mut reader = MessageReader::new(ours);
+
+        let length = MAX_MESSAGE_LENGTH as u32 + 1;
+        peer.write_all(&length.to_be_bytes()).await.unwrap();
+
+        let err = reader.read().await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn reads_a_message_split_across_reads() {
+        let (mut peer, ours) = duplex(64);
+        let mut reader = MessageReader::new(ours);
+        let bytes = PeerMessage::piece(1, 16, &[9; 10]).encode();
+
+        let (first, second) = bytes.split_at(6);
+        peer.write_all(first).await.unwrap();
+        // the partial message stays buffered when the read is cancelled
+        let partial = tokio::time::timeout(Duration::from_millis(10), reader.read()).await;
+        assert!(partial.is_err());
+        peer.write_all(second).await.unwrap();
+
+        let message = reader.read().await.unwrap();
+        assert_eq!(message.id, MessageType::Piece);
+        assert_eq!(message.payload, PeerMessage::piece(1, 16, &[9; 10]).payload);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_peer_closes_mid_message() {
+        let (mut peer, ours) = duplex(64);
+        let mut reader = MessageReader::new(ours);
 
-    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        let length = u32::from_be_bytes(value[..4].try_into().unwrap());
-        let id = value[4].try_into().unwrap();
-        let payload: Vec<u8> = value[5..length as usize].try_into().unwrap();
+        peer.write_all(&[0, 0, 0, 5, 4, 0]).await.unwrap();
+        drop(peer);
 
-        Ok(PeerMessage { id, payload })
+        assert!(reader.read().await.is_err());
     }
 }
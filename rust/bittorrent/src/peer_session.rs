@@ -0,0 +1,418 @@
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+
+use crate::bitfield::Bitfield;
+use crate::extension::{ExtensionHandshake, EXTENDED_HANDSHAKE_ID};
+use crate::peer_message::{MessageReader, MessageType, PeerMessage, KEEP_ALIVE_INTERVAL};
+use crate::piece_download::{PieceDownload, BLOCK_SIZE};
+use crate::trackers::{Peer, PeerHandshake};
+
+/// How long a peer gets to accept our connection and answer our handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a peer may keep us choked before we give up on it.
+pub const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a peer may take to send any of the blocks we requested.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of block requests kept in flight when not configured otherwise.
+pub const DEFAULT_PIPELINE_DEPTH: usize = 16;
+
+/// A connection to a peer which outlives single pieces, keeping track of
+/// the state both sides have announced.
+pub struct PeerSession {
+    pub addr: SocketAddr,
+    reader: MessageReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+
+    /// When we last sent a message, to know when a keep-alive is due.
+    last_sent: Instant,
+
+    /// The handshake the peer answered ours with.
+    pub handshake: PeerHandshake,
+
+    /// The peer's extension handshake, once received.
+    pub extensions: Option<ExtensionHandshake>,
+
+    /// The pieces the peer has, from its `Bitfield` and `Have` messages.
+    pub bitfield: Bitfield,
+
+    /// The pieces added to `bitfield` since the last
+    /// [`PeerSession::take_new_pieces`].
+    new_pieces: Vec<usize>,
+
+    /// The number of pieces of the torrent, see
+    /// [`PeerSession::set_no_of_pieces`].
+    no_of_pieces: Option<usize>,
+
+    pub am_interested: bool,
+    pub peer_choking: bool,
+
+    /// The maximum number of block requests to keep in flight.
+    pub pipeline_depth: usize,
+}
+
+impl PeerSession {
+    /// Connects and handshakes with the peer, sending our extension handshake
+    /// when both sides support the extension protocol. Fails when the peer
+    /// does not answer within [`CONNECT_TIMEOUT`].
+    pub async fn connect(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: String,
+    ) -> anyhow::Result<PeerSession> {
+        let handshake = PeerHandshake::from(info_hash, peer_id);
+
+        let connected = async {
+            let mut stream = TcpStream::connect(addr).await?;
+            handshake.write_to_stream(&mut stream).await?;
+            let handshake_response = PeerHandshake::read_from_stream(&mut stream).await?;
+            anyhow::Ok((stream, handshake_response))
+        };
+
+        let (stream, handshake_response) = tokio::time::timeout(CONNECT_TIMEOUT, connected)
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Peer {} did not answer our handshake within {:?}",
+                    addr,
+                    CONNECT_TIMEOUT
+                )
+            })??;
+
+        if handshake_response.info_hash != info_hash {
+            anyhow::bail!("Peer {} answered with a different info hash", addr);
+        }
+
+        let (reader, writer) = stream.into_split();
+        let mut session = PeerSession {
+            addr,
+            reader: MessageReader::new(reader),
+            writer,
+            last_sent: Instant::now(),
+            handshake: handshake_response,
+            extensions: None,
+            bitfield: Bitfield::new(0),
+            new_pieces: vec![],
+            no_of_pieces: None,
+            am_interested: false,
+            peer_choking: true,
+            pipeline_depth: DEFAULT_PIPELINE_DEPTH,
+        };
+
+        if handshake.supports_extensions() && session.supports_extensions() {
+            session
+                .send(&ExtensionHandshake::ours().to_message())
+                .await?;
+        }
+
+        Ok(session)
+    }
+
+    /// Connects to a peer returned by a tracker, making sure it is the peer
+    /// the tracker knows when it gave its peer id.
+    pub async fn connect_to_peer(
+        peer: &Peer,
+        info_hash: [u8; 20],
+        peer_id: String,
+    ) -> anyhow::Result<PeerSession> {
+        let session = PeerSession::connect(peer.resolve().await?, info_hash, peer_id).await?;
+
+        match peer.peer_id {
+            Some(expected) if expected != session.handshake.peer_id => {
+                anyhow::bail!("Peer {} answered with a different peer id", peer)
+            }
+            _ => Ok(session),
+        }
+    }
+
+    /// Starts keeping track of the pieces the peer has. Until the number of
+    /// pieces is known, as when fetching the metadata of a magnet link, the
+    /// `Bitfield` and `Have` messages cannot be checked and are ignored.
+    pub fn set_no_of_pieces(&mut self, no_of_pieces: usize) {
+        self.no_of_pieces = Some(no_of_pieces);
+        self.bitfield = Bitfield::new(no_of_pieces);
+        self.new_pieces.clear();
+    }
+
+    /// Returns the pieces the peer announced since the last call, so that
+    /// they can be counted without going over the whole bitfield.
+    pub fn take_new_pieces(&mut self) -> Vec<usize> {
+        std::mem::take(&mut self.new_pieces)
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.handshake.supports_extensions()
+    }
+
+    /// Sends a message, keeping track of our interest state.
+    pub async fn send(&mut self, message: &PeerMessage) -> anyhow::Result<()> {
+        match message.id {
+            MessageType::Interested => self.am_interested = true,
+            MessageType::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+
+        message.write(&mut self.writer).await?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    /// Reads the next message, applying the state changes it carries, and
+    /// sends keep-alives while waiting for it.
+    pub async fn read_message(&mut self) -> anyhow::Result<PeerMessage> {
+        match self.read_message_until(std::future::pending()).await? {
+            Some(message) => Ok(message),
+            None => unreachable!("the wait never ends"),
+        }
+    }
+
+    /// Like [`PeerSession::read_message`], but gives up and returns `None`
+    /// once `wake` completes. Nothing is lost when giving up, a message only
+    /// partially received is read by the next call.
+    pub async fn read_message_until(
+        &mut self,
+        wake: impl Future<Output = ()>,
+    ) -> anyhow::Result<Option<PeerMessage>> {
+        tokio::pin!(wake);
+
+        let message = loop {
+            let keep_alive_at = self.last_sent + KEEP_ALIVE_INTERVAL;
+            tokio::select! {
+                _ = &mut wake => return Ok(None),
+                message = self.reader.read() => break message?,
+                _ = tokio::time::sleep_until(keep_alive_at) => {
+                    self.send(&PeerMessage::keep_alive()).await?;
+                }
+            }
+        };
+
+        match message.id {
+            MessageType::Choke => self.peer_choking = true,
+            MessageType::Unchoke => self.peer_choking = false,
+            MessageType::Have => {
+                if let Some(no_of_pieces) = self.no_of_pieces {
+                    let piece_index = read_u32(&message.payload, 0)? as usize;
+                    if piece_index >= no_of_pieces {
+                        anyhow::bail!(
+                            "Peer {} announced piece {} of a torrent with {} pieces",
+                            self.addr,
+                            piece_index,
+                            no_of_pieces
+                        );
+                    }
+                    self.add_piece(piece_index);
+                }
+            }
+            MessageType::Bitfield => {
+                if let Some(no_of_pieces) = self.no_of_pieces {
+                    let bitfield = Bitfield::from_payload(message.payload.clone(), no_of_pieces)?;
+                    (0..no_of_pieces)
+                        .filter(|i| bitfield.has(*i))
+                        .for_each(|i| self.add_piece(i));
+                }
+            }
+            MessageType::Extended if message.payload.first() == Some(&EXTENDED_HANDSHAKE_ID) => {
+                self.extensions = Some(ExtensionHandshake::from_payload(&message.payload[1..])?);
+            }
+            _ => {}
+        }
+
+        Ok(Some(message))
+    }
+
+    fn add_piece(&mut self, piece_index: usize) {
+        if !self.bitfield.has(piece_index) {
+            self.bitfield.set(piece_index);
+            self.new_pieces.push(piece_index);
+        }
+    }
+
+    /// Waits for the peer's extension handshake, only call this when
+    /// [`PeerSession::supports_extensions`].
+    pub async fn wait_for_extensions(&mut self) -> anyhow::Result<&ExtensionHandshake> {
+        while self.extensions.is_none() {
+            self.read_message().await?;
+        }
+
+        Ok(self.extensions.as_ref().unwrap())
+    }
+
+    /// Tells the peer we are interested and waits until it unchokes us,
+    /// failing after [`UNCHOKE_TIMEOUT`].
+    pub async fn wait_for_unchoke(&mut self) -> anyhow::Result<()> {
+        if !self.am_interested {
+            self.send(&PeerMessage::from_empty_payload(MessageType::Interested))
+                .await?;
+        }
+
+        let addr = self.addr;
+        let unchoked = async {
+            while self.peer_choking {
+                self.read_message().await?;
+            }
+            Ok(())
+        };
+
+        tokio::time::timeout(UNCHOKE_TIMEOUT, unchoked)
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "Peer {} did not unchoke us within {:?}",
+                    addr,
+                    UNCHOKE_TIMEOUT
+                )
+            })?
+    }
+
+    /// The number of block requests kept in flight, bounded by the `reqq`
+    /// the peer advertised in its extension handshake.
+    fn request_window(&self) -> usize {
+        let reqq = self.extensions.as_ref().and_then(|e| e.reqq);
+
+        reqq.map_or(self.pipeline_depth, |reqq| self.pipeline_depth.min(reqq))
+            .max(1)
+    }
+
+    /// Downloads the blocks of a piece, keeping up to
+    /// [`PeerSession::request_window`] block requests in flight. Blocks are
+    /// matched by their offset as peers may answer out of order, and a peer
+    /// choking us drops our pending requests so they are sent again once
+    /// unchoked.
+    ///
+    /// Requests for blocks which another peer sent first are cancelled, the
+    /// data of the piece is returned by the peer sending the last missing
+    /// block and `None` by the others. The download fails when no requested
+    /// block arrives for [`REQUEST_TIMEOUT`].
+    pub async fn download_piece(
+        &mut self,
+        piece: &PieceDownload,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut in_flight: BTreeSet<usize> = BTreeSet::new();
+        let result = self.download_blocks(piece, &mut in_flight).await;
+        in_flight
+            .iter()
+            .for_each(|block| piece.release_block(*block));
+
+        result
+    }
+
+    async fn download_blocks(
+        &mut self,
+        piece: &PieceDownload,
+        in_flight: &mut BTreeSet<usize>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut deadline = Instant::now() + REQUEST_TIMEOUT;
+
+        loop {
+            // created first so that no block arriving from now on is missed
+            let received = piece.received.notified();
+
+            self.cancel_received_blocks(piece, in_flight).await?;
+            if piece.is_complete() {
+                return Ok(None);
+            }
+
+            if self.peer_choking {
+                in_flight
+                    .iter()
+                    .for_each(|block| piece.release_block(*block));
+                in_flight.clear();
+
+                self.wait_for_unchoke().await?;
+                deadline = Instant::now() + REQUEST_TIMEOUT;
+                continue;
+            }
+
+            while in_flight.len() < self.request_window() {
+                let block = match piece.request_block(in_flight) {
+                    Some(block) => block,
+                    None => break,
+                };
+
+                in_flight.insert(block);
+                self.send(&piece.request(block)).await?;
+            }
+
+            let wake = async {
+                tokio::select! {
+                    _ = received => {}
+                    _ = tokio::time::sleep_until(deadline) => {}
+                }
+            };
+            let message = match self.read_message_until(wake).await? {
+                Some(message) => message,
+                None if Instant::now() >= deadline => anyhow::bail!(
+                    "Peer {} sent no block of piece {} within {:?}",
+                    self.addr,
+                    piece.index,
+                    REQUEST_TIMEOUT
+                ),
+                None => continue,
+            };
+            if message.id != MessageType::Piece {
+                continue;
+            }
+
+            let index = read_u32(&message.payload, 0)? as usize;
+            let begin = read_u32(&message.payload, 4)? as usize;
+            let block = begin / BLOCK_SIZE;
+            if index != piece.index || begin % BLOCK_SIZE != 0 || !in_flight.contains(&block) {
+                continue;
+            }
+
+            let block_data = &message.payload[8..];
+            if block_data.len() != piece.block_length(block) {
+                anyhow::bail!(
+                    "Expected a block of {} bytes from {} but got {}",
+                    piece.block_length(block),
+                    self.addr,
+                    block_data.len()
+                );
+            }
+
+            in_flight.remove(&block);
+            deadline = Instant::now() + REQUEST_TIMEOUT;
+            if let Some(data) = piece.add_block(block, block_data) {
+                self.cancel_received_blocks(piece, in_flight).await?;
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    /// Cancels our requests for the blocks another peer sent first.
+    async fn cancel_received_blocks(
+        &mut self,
+        piece: &PieceDownload,
+        in_flight: &mut BTreeSet<usize>,
+    ) -> anyhow::Result<()> {
+        let received: Vec<usize> = in_flight
+            .iter()
+            .copied()
+            .filter(|block| piece.has_block(*block))
+            .collect();
+
+        for block in received {
+            in_flight.remove(&block);
+            piece.release_block(block);
+            self.send(&piece.cancel(block)).await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn read_u32(payload: &[u8], offset: usize) -> anyhow::Result<u32> {
+    payload
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| anyhow::anyhow!("Message payload is too short"))
+}
@@ -0,0 +1,117 @@
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+use tokio::sync::Notify;
+
+use crate::peer_message::PeerMessage;
+
+/// Pieces are requested in blocks of 16 KiB, the last may be shorter.
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+/// A piece being downloaded, shared by the peers downloading it. In endgame
+/// mode several peers request the same blocks: the first copy of a block to
+/// arrive is kept, and the other peers are woken up to cancel their
+/// requests for it.
+pub struct PieceDownload {
+    pub index: usize,
+    pub length: usize,
+    blocks: Mutex<Blocks>,
+
+    /// Notified whenever a block arrives.
+    pub received: Notify,
+}
+
+struct Blocks {
+    data: Vec<u8>,
+    received: Vec<bool>,
+
+    /// The number of peers each block is requested from.
+    requested: Vec<usize>,
+    remaining: usize,
+}
+
+impl PieceDownload {
+    pub fn new(index: usize, length: usize) -> PieceDownload {
+        let no_of_blocks = (length + BLOCK_SIZE - 1) / BLOCK_SIZE;
+
+        PieceDownload {
+            index,
+            length,
+            blocks: Mutex::new(Blocks {
+                data: vec![0; length],
+                received: vec![false; no_of_blocks],
+                requested: vec![0; no_of_blocks],
+                remaining: no_of_blocks,
+            }),
+            received: Notify::new(),
+        }
+    }
+
+    pub fn block_length(&self, block: usize) -> usize {
+        std::cmp::min(BLOCK_SIZE, self.length - block * BLOCK_SIZE)
+    }
+
+    /// Picks the next block to request from a peer with the given requests
+    /// in flight: a missing block, preferably one no other peer was asked
+    /// for.
+    pub fn request_block(&self, in_flight: &BTreeSet<usize>) -> Option<usize> {
+        let mut blocks = self.blocks.lock().unwrap();
+
+        let block = (0..blocks.received.len())
+            .filter(|block| !blocks.received[*block] && !in_flight.contains(block))
+            .min_by_key(|block| blocks.requested[*block])?;
+        blocks.requested[block] += 1;
+
+        Some(block)
+    }
+
+    /// Forgets a request which was cancelled or will not be answered.
+    pub fn release_block(&self, block: usize) {
+        self.blocks.lock().unwrap().requested[block] -= 1;
+    }
+
+    pub fn has_block(&self, block: usize) -> bool {
+        self.blocks.lock().unwrap().received[block]
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks.lock().unwrap().remaining == 0
+    }
+
+    /// Stores a requested block of the right length unless another peer
+    /// sent it first, and returns the data of the piece when it was the
+    /// last block missing.
+    pub fn add_block(&self, block: usize, data: &[u8]) -> Option<Vec<u8>> {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks.requested[block] -= 1;
+        if std::mem::replace(&mut blocks.received[block], true) {
+            return None;
+        }
+
+        let begin = block * BLOCK_SIZE;
+        blocks.data[begin..begin + data.len()].copy_from_slice(data);
+        blocks.remaining -= 1;
+        self.received.notify_waiters();
+
+        match blocks.remaining {
+            0 => Some(std::mem::take(&mut blocks.data)),
+            _ => None,
+        }
+    }
+
+    pub fn request(&self, block: usize) -> PeerMessage {
+        PeerMessage::request(
+            self.index as u32,
+            (block * BLOCK_SIZE) as u32,
+            self.block_length(block) as u32,
+        )
+    }
+
+    pub fn cancel(&self, block: usize) -> PeerMessage {
+        PeerMessage::cancel(
+            self.index as u32,
+            (block * BLOCK_SIZE) as u32,
+            self.block_length(block) as u32,
+        )
+    }
+}
@@ -0,0 +1,20 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// A random number good enough for transaction ids and shuffling, taken from
+/// the randomly seeded keys of the standard library's hasher.
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+pub fn random_u32() -> u32 {
+    random_u64() as u32
+}
+
+/// Shuffles the items in place (Fisher-Yates).
+pub fn shuffle<T>(items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        let j = (random_u64() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
@@ -0,0 +1,300 @@
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Instant;
+
+use crate::announcer::TransferStats;
+use crate::bitfield::Bitfield;
+use crate::choker::{ChokedPeer, Choker};
+use crate::peer_message::{MessageReader, MessageType, PeerMessage, KEEP_ALIVE_INTERVAL};
+use crate::storage::Storage;
+use crate::torrent::TorrentFile;
+use crate::trackers::PeerHandshake;
+
+/// The port we accept peers on, as announced to the trackers.
+pub const LISTEN_PORT: u16 = 6881;
+
+/// Requests for larger blocks are ignored, clients use 16 KiB.
+const MAX_REQUEST_LENGTH: usize = 1 << 17;
+
+/// Messages read ahead of the requests being served, so that a `Cancel`
+/// can still catch the request it cancels.
+const READ_AHEAD: usize = 64;
+
+/// Piece completions queued for each connection before older ones are lost.
+const COMPLETED_BACKLOG: usize = 1024;
+
+/// A torrent we upload to other peers, either complete or still being
+/// downloaded in which case only the pieces we have are offered.
+pub struct SeededTorrent {
+    pub torrent: Arc<TorrentFile>,
+    storage: Mutex<Storage>,
+    stats: Arc<TransferStats>,
+
+    /// The pieces we have, sent in our `Bitfield` message.
+    have: Mutex<Bitfield>,
+
+    /// Pieces completed while uploading, announced to every connected peer
+    /// with a `Have` message.
+    completed: broadcast::Sender<usize>,
+
+    /// Decides which of the connected peers are uploaded to, see
+    /// [`Choker::run`].
+    pub choker: Choker,
+}
+
+impl SeededTorrent {
+    pub fn new(
+        torrent: Arc<TorrentFile>,
+        storage: Storage,
+        have: Bitfield,
+        stats: Arc<TransferStats>,
+        upload_slots: usize,
+    ) -> SeededTorrent {
+        SeededTorrent {
+            torrent,
+            storage: Mutex::new(storage),
+            stats,
+            have: Mutex::new(have),
+            completed: broadcast::channel(COMPLETED_BACKLOG).0,
+            choker: Choker::new(upload_slots),
+        }
+    }
+
+    /// Stores a downloaded piece and offers it to the connected peers.
+    pub fn write_piece(&self, piece_index: usize, data: &[u8]) -> anyhow::Result<()> {
+        self.storage
+            .lock()
+            .unwrap()
+            .write_piece(piece_index, data)?;
+        self.have.lock().unwrap().set(piece_index);
+        let _ = self.completed.send(piece_index);
+
+        Ok(())
+    }
+
+    pub fn finish(&self) -> anyhow::Result<()> {
+        self.storage.lock().unwrap().finish()
+    }
+}
+
+/// Accepts peers on `port`, serving each peer asking for one of the torrents
+/// in a task of its own.
+pub async fn listen(port: u16, torrents: Vec<Arc<SeededTorrent>>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let torrents = torrents.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve_peer(stream, &torrents).await {
+                eprintln!("Peer {} failed: {}", addr, err);
+            }
+        });
+    }
+}
+
+/// Answers the handshake of an incoming peer and uploads the blocks it
+/// requests until it disconnects.
+async fn serve_peer(mut stream: TcpStream, torrents: &[Arc<SeededTorrent>]) -> anyhow::Result<()> {
+    let handshake = PeerHandshake::read_from_stream(&mut stream).await?;
+    let seeded = torrents
+        .iter()
+        .find(|seeded| seeded.torrent.info_hash() == handshake.info_hash)
+        .ok_or_else(|| anyhow::anyhow!("Peer asked for a torrent we do not have"))?;
+
+    // uploading needs no extension, so none are advertised
+    PeerHandshake::from(handshake.info_hash, "00112233445566778899".to_string())
+        .without_extensions()
+        .write_to_stream(&mut stream)
+        .await?;
+
+    let (reader, writer) = stream.into_split();
+    let (tx, rx) = mpsc::channel(READ_AHEAD);
+    let read_task = tokio::spawn(read_messages(reader, tx));
+
+    let (peer, commands) = seeded.choker.register(handshake.peer_id);
+    let mut upload = Upload {
+        seeded,
+        writer,
+        last_sent: Instant::now(),
+        peer: peer.clone(),
+        am_choking: true,
+        requests: VecDeque::new(),
+    };
+    let result = upload.run(rx, commands).await;
+    read_task.abort();
+    seeded.choker.unregister(&peer);
+
+    result
+}
+
+async fn read_messages(reader: OwnedReadHalf, tx: mpsc::Sender<PeerMessage>) -> anyhow::Result<()> {
+    let mut reader = MessageReader::new(reader);
+
+    loop {
+        let message = reader.read().await?;
+        if tx.send(message).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// The uploading side of a connection.
+struct Upload<'a> {
+    seeded: &'a SeededTorrent,
+    writer: OwnedWriteHalf,
+    last_sent: Instant,
+
+    /// Our side of the connection as known to the choker.
+    peer: Arc<ChokedPeer>,
+    am_choking: bool,
+
+    /// Requested blocks not served yet: piece index, offset and length.
+    requests: VecDeque<(usize, usize, usize)>,
+}
+
+impl Upload<'_> {
+    /// Uploads until the peer disconnects, choking and unchoking it as told
+    /// by the choker over `commands`.
+    async fn run(
+        &mut self,
+        mut rx: mpsc::Receiver<PeerMessage>,
+        mut commands: mpsc::UnboundedReceiver<bool>,
+    ) -> anyhow::Result<()> {
+        // subscribed first so that no piece completed meanwhile is missed
+        let mut completed = self.seeded.completed.subscribe();
+        let bitfield = self.seeded.have.lock().unwrap().clone();
+        self.send(&PeerMessage {
+            id: MessageType::Bitfield,
+            payload: bitfield.to_payload(),
+        })
+        .await?;
+
+        loop {
+            // handle everything the peer sent before serving the next block
+            while let Ok(message) = rx.try_recv() {
+                self.handle(message).await?;
+            }
+            while let Ok(choke) = commands.try_recv() {
+                self.set_choking(choke).await?;
+            }
+
+            if let Some(request) = self.requests.pop_front() {
+                self.serve(request).await?;
+                continue;
+            }
+
+            tokio::select! {
+                message = rx.recv() => match message {
+                    Some(message) => self.handle(message).await?,
+                    None => return Ok(()),
+                },
+                Some(choke) = commands.recv() => self.set_choking(choke).await?,
+                Ok(piece_index) = completed.recv() => {
+                    self.send(&PeerMessage::have(piece_index as u32)).await?;
+                }
+                _ = tokio::time::sleep_until(self.last_sent + KEEP_ALIVE_INTERVAL) => {
+                    self.send(&PeerMessage::keep_alive()).await?;
+                }
+            }
+        }
+    }
+
+    async fn handle(&mut self, message: PeerMessage) -> anyhow::Result<()> {
+        match message.id {
+            MessageType::Interested => self.seeded.choker.set_interested(&self.peer, true),
+            MessageType::NotInterested => {
+                self.seeded.choker.set_interested(&self.peer, false);
+                self.requests.clear();
+            }
+            MessageType::Request if !self.am_choking => {
+                let request = read_request(&message.payload)?;
+                if !self.requests.contains(&request) {
+                    self.requests.push_back(request);
+                }
+            }
+            MessageType::Cancel => {
+                let request = read_request(&message.payload)?;
+                self.requests.retain(|r| *r != request);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    async fn set_choking(&mut self, choke: bool) -> anyhow::Result<()> {
+        if choke == self.am_choking {
+            return Ok(());
+        }
+
+        self.am_choking = choke;
+        let id = if choke {
+            // requests are dropped when choking, the peer asks again later
+            self.requests.clear();
+            MessageType::Choke
+        } else {
+            MessageType::Unchoke
+        };
+
+        self.send(&PeerMessage::from_empty_payload(id)).await
+    }
+
+    async fn send(&mut self, message: &PeerMessage) -> anyhow::Result<()> {
+        message.write(&mut self.writer).await?;
+        self.last_sent = Instant::now();
+
+        Ok(())
+    }
+
+    /// Reads a requested block from the disk and sends it, requests which
+    /// are out of bounds, too large or for pieces we do not have are ignored.
+    async fn serve(
+        &mut self,
+        (piece_index, begin, length): (usize, usize, usize),
+    ) -> anyhow::Result<()> {
+        let torrent = &self.seeded.torrent;
+        if piece_index >= torrent.get_no_of_pieces()
+            || !self.seeded.have.lock().unwrap().has(piece_index)
+            || length == 0
+            || length > MAX_REQUEST_LENGTH
+            || begin + length > torrent.get_piece_length(piece_index)
+        {
+            return Ok(());
+        }
+
+        let offset = piece_index * torrent.info.piece_length + begin;
+        let block = self.seeded.storage.lock().unwrap().read(offset, length)?;
+
+        self.send(&PeerMessage::piece(
+            piece_index as u32,
+            begin as u32,
+            &block,
+        ))
+        .await?;
+        self.seeded
+            .stats
+            .uploaded
+            .fetch_add(length, Ordering::Relaxed);
+        self.peer.uploaded.fetch_add(length, Ordering::Relaxed);
+
+        Ok(())
+    }
+}
+
+/// The piece index, offset and length of a `Request` or `Cancel` message.
+fn read_request(payload: &[u8]) -> anyhow::Result<(usize, usize, usize)> {
+    if payload.len() != 12 {
+        anyhow::bail!("Expected a payload of 12 bytes but got {}", payload.len());
+    }
+
+    let field = |i: usize| u32::from_be_bytes(payload[i..i + 4].try_into().unwrap()) as usize;
+
+    Ok((field(0), field(4), field(8)))
+}
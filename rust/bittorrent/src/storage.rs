@@ -0,0 +1,155 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::hash::hex_sha1;
+use crate::torrent::{get_file_segments, FileLayout, FileSegment, TorrentFile};
+
+/// When written pieces are flushed to the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// Leave flushing to the operating system.
+    Never,
+    /// Flush the files a piece touches after writing it.
+    Piece,
+    /// Flush all files once the download is complete.
+    Completion,
+}
+
+struct StorageFile {
+    file: File,
+
+    /// Whether the file was created by [`Storage::open`], as opposed to left
+    /// over from an earlier run.
+    created: bool,
+}
+
+/// The files of a torrent on disk, pieces are written at their offset as
+/// soon as they are verified.
+pub struct Storage {
+    layouts: Vec<FileLayout>,
+    files: Vec<StorageFile>,
+    piece_length: usize,
+    fsync: FsyncPolicy,
+}
+
+/// Where a file of the torrent is stored: single-file torrents are stored at
+/// `output` itself, multi-file torrents have their tree inside `output`.
+pub fn file_path(torrent: &TorrentFile, output: &Path, layout: &FileLayout) -> PathBuf {
+    if torrent.is_multi_file() {
+        output.join(&layout.path)
+    } else {
+        output.to_path_buf()
+    }
+}
+
+impl Storage {
+    /// Creates (or opens) the files of the torrent and preallocates them to
+    /// their full length, see [`file_path`] for where they are stored.
+    pub fn open(
+        torrent: &TorrentFile,
+        output: &Path,
+        fsync: FsyncPolicy,
+    ) -> anyhow::Result<Storage> {
+        let layouts = torrent.get_file_layout();
+
+        let mut files = vec![];
+        for layout in &layouts {
+            let path = file_path(torrent, output, layout);
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            let created = !path.exists();
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            file.set_len(layout.length as u64)?;
+
+            files.push(StorageFile { file, created });
+        }
+
+        Ok(Storage {
+            layouts,
+            files,
+            piece_length: torrent.info.piece_length,
+            fsync,
+        })
+    }
+
+    /// Writes a verified piece, splitting it over the files it straddles.
+    pub fn write_piece(&mut self, piece_index: usize, data: &[u8]) -> anyhow::Result<()> {
+        let segments = self.file_segments(piece_index * self.piece_length, data.len());
+
+        for segment in &segments {
+            let file = &mut self.files[segment.file_index].file;
+            file.seek(SeekFrom::Start(segment.file_offset as u64))?;
+            file.write_all(&data[segment.data_offset..segment.data_offset + segment.length])?;
+        }
+
+        if self.fsync == FsyncPolicy::Piece {
+            for segment in &segments {
+                self.files[segment.file_index].file.sync_data()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Hashes the pieces already on disk, returning the indices of the ones
+    /// which are missing or do not match their hash. Pieces lying entirely in
+    /// files created by this run are known to be missing and are not read.
+    pub fn missing_pieces(&mut self, torrent: &TorrentFile) -> anyhow::Result<Vec<usize>> {
+        let mut missing = vec![];
+
+        for piece_index in 0..torrent.get_no_of_pieces() {
+            let offset = piece_index * self.piece_length;
+            let length = torrent.get_piece_length(piece_index);
+
+            let segments = self.file_segments(offset, length);
+            let created = segments
+                .iter()
+                .all(|segment| self.files[segment.file_index].created);
+
+            if created
+                || hex_sha1(&self.read(offset, length)?) != torrent.get_piece_hash(piece_index)
+            {
+                missing.push(piece_index);
+            }
+        }
+
+        Ok(missing)
+    }
+
+    /// Reads a range of the concatenated piece data.
+    pub fn read(&mut self, offset: usize, length: usize) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+
+        for segment in self.file_segments(offset, length) {
+            let file = &mut self.files[segment.file_index].file;
+            file.seek(SeekFrom::Start(segment.file_offset as u64))?;
+            file.read_exact(&mut data[segment.data_offset..segment.data_offset + segment.length])?;
+        }
+
+        Ok(data)
+    }
+
+    fn file_segments(&self, offset: usize, length: usize) -> Vec<FileSegment> {
+        get_file_segments(&self.layouts, offset, length)
+    }
+
+    /// Called once every piece is written.
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        if self.fsync == FsyncPolicy::Completion {
+            for file in &self.files {
+                file.file.sync_all()?;
+            }
+        }
+
+        Ok(())
+    }
+}
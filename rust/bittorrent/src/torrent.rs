@@ -1,7 +1,21 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use serde_bytes;
+use std::path::PathBuf;
 use std::str;
 
+use crate::bencode::find_raw_dictionary_value;
+use crate::hash::b_sha1;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct TorrentFileEntry {
+    /// The length of the file, in bytes.
+    pub length: usize,
+
+    /// A list of UTF-8 encoded strings corresponding to subdirectory names,
+    /// the last of which is the actual file name (a zero length list is an error case).
+    pub path: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct TorrentFileInfo {
     /// The name key maps to a UTF-8 encoded string which is the suggested
@@ -19,7 +33,15 @@ pub struct TorrentFileInfo {
     pub piece_length: usize,
 
     /// The length of the file, in bytes.
-    pub length: usize,
+    /// Only present in single-file torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+
+    /// The files of a multi-file torrent, in the order they are laid out
+    /// in the concatenated piece data.
+    /// Only present in multi-file torrents.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<TorrentFileEntry>>,
 
     /// a string whose length is a multiple of 20.
     /// It is to be subdivided into strings of length 20, each of which
@@ -28,35 +50,453 @@ pub struct TorrentFileInfo {
     pub pieces: Vec<u8>,
 }
 
+/// A file of the torrent as it is laid out on disk.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FileLayout {
+    /// The path of the file, relative to the download root.
+    pub path: PathBuf,
+
+    /// The length of the file, in bytes.
+    pub length: usize,
+
+    /// The offset of the first byte of the file in the concatenated piece data.
+    pub offset: usize,
+}
+
+/// The part of a file covered by a range of the concatenated piece data.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct FileSegment {
+    /// The index of the file in the layout.
+    pub file_index: usize,
+
+    /// Where the segment starts in the file.
+    pub file_offset: usize,
+
+    /// Where the segment starts in the range.
+    pub data_offset: usize,
+
+    pub length: usize,
+}
+
+/// Maps a range of the concatenated piece data to the files it covers, a
+/// piece straddling file boundaries maps to several segments.
+pub fn get_file_segments(files: &[FileLayout], offset: usize, length: usize) -> Vec<FileSegment> {
+    let end = offset + length;
+
+    files
+        .iter()
+        .enumerate()
+        .filter_map(|(file_index, file)| {
+            let file_start = file.offset;
+            let file_end = file_start + file.length;
+
+            let start = offset.max(file_start);
+            let stop = end.min(file_end);
+            if start >= stop {
+                return None;
+            }
+
+            Some(FileSegment {
+                file_index,
+                file_offset: start - file_start,
+                data_offset: start - offset,
+                length: stop - start,
+            })
+        })
+        .collect()
+}
+
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub struct TorrentFile {
-    /// The URL of the tracker.
-    pub announce: String,
+    /// The URL of the tracker, optional when `announce-list` is given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announce: Option<String>,
+
+    /// Tiers of tracker URLs (BEP 12), superseding `announce` when present.
+    #[serde(
+        default,
+        rename = "announce-list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
 
     pub info: TorrentFileInfo,
+
+    /// The bytes of the info dictionary exactly as they appeared in the
+    /// torrent file, re-encoding `info` would drop the keys it does not model.
+    #[serde(skip)]
+    info_bytes: Vec<u8>,
 }
 
 impl TorrentFile {
-    pub fn from_u8_vec(vec: Vec<u8>) -> TorrentFile {
-        let torrent: TorrentFile = serde_bencode::from_bytes(&vec[..]).unwrap();
+    pub fn from_u8_vec(vec: Vec<u8>) -> anyhow::Result<TorrentFile> {
+        let mut torrent: TorrentFile =
+            serde_bencode::from_bytes(&vec[..]).context("Could not parse the torrent file")?;
+        torrent.info_bytes = find_raw_dictionary_value(&vec, b"info")?
+            .ok_or_else(|| anyhow::anyhow!("Expected the torrent file to have an info dictionary"))?
+            .to_vec();
+
+        torrent.validate()?;
 
-        if torrent.info.pieces.len() % 20 != 0 {
-            panic!(
+        Ok(torrent)
+    }
+
+    /// Builds a torrent from an info dictionary received from peers (BEP 9),
+    /// the caller is responsible for checking it against the info hash. Each
+    /// of the trackers is put in a tier of its own.
+    pub fn from_info_bytes(
+        trackers: &[String],
+        info_bytes: Vec<u8>,
+    ) -> anyhow::Result<TorrentFile> {
+        let torrent = TorrentFile {
+            announce: trackers.first().cloned(),
+            announce_list: match trackers.len() {
+                0 | 1 => None,
+                _ => Some(trackers.iter().map(|t| vec![t.clone()]).collect()),
+            },
+            info: serde_bencode::from_bytes(&info_bytes)?,
+            info_bytes,
+        };
+
+        torrent.validate()?;
+
+        Ok(torrent)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.info.pieces.len() % 20 != 0 {
+            anyhow::bail!(
                 "Expected pieces to have a length which is a multiple of 20 but found: {}",
-                torrent.info.pieces.len()
+                self.info.pieces.len()
+            );
+        }
+
+        match (&self.info.length, &self.info.files) {
+            (Some(_), None) | (None, Some(_)) => {}
+            _ => {
+                anyhow::bail!("Expected the info dictionary to have exactly one of length or files")
+            }
+        }
+
+        if let Some(file) = self.info.files.iter().flatten().find(|f| f.path.is_empty()) {
+            anyhow::bail!(
+                "Expected every file to have a path but found a file of {} bytes without one",
+                file.length
+            );
+        }
+
+        if self.info.piece_length == 0 {
+            anyhow::bail!("Expected a piece length greater than 0");
+        }
+
+        let expected_pieces =
+            (self.get_total_length() + self.info.piece_length - 1) / self.info.piece_length;
+        if self.get_no_of_pieces() != expected_pieces {
+            anyhow::bail!(
+                "Expected {} piece hashes for {} bytes in pieces of {} bytes but found {}",
+                expected_pieces,
+                self.get_total_length(),
+                self.info.piece_length,
+                self.get_no_of_pieces()
             );
         }
 
-        torrent
+        Ok(())
+    }
+
+    /// The tracker tiers to announce to: `announce-list` if it has any
+    /// trackers, otherwise a single tier with `announce`.
+    pub fn get_tracker_tiers(&self) -> Vec<Vec<String>> {
+        let tiers: Vec<Vec<String>> = self
+            .announce_list
+            .iter()
+            .flatten()
+            .filter(|tier| !tier.is_empty())
+            .cloned()
+            .collect();
+
+        if tiers.is_empty() {
+            self.announce.iter().map(|url| vec![url.clone()]).collect()
+        } else {
+            tiers
+        }
+    }
+
+    /// The SHA1 hash of the bencoded info dictionary, identifying the torrent.
+    pub fn info_hash(&self) -> [u8; 20] {
+        b_sha1(&self.info_bytes).try_into().unwrap()
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        self.info.files.is_some()
+    }
+
+    pub fn get_total_length(&self) -> usize {
+        match &self.info.files {
+            Some(files) => files.iter().map(|f| f.length).sum(),
+            None => self.info.length.unwrap_or(0),
+        }
     }
 
     pub fn get_no_of_pieces(&self) -> usize {
         self.info.pieces.len() / 20
     }
 
+    /// The length of the piece at the given index, the last piece may be
+    /// shorter than `piece length`.
+    pub fn get_piece_length(&self, piece_index: usize) -> usize {
+        let start = self.info.piece_length * piece_index;
+
+        std::cmp::min(self.info.piece_length, self.get_total_length() - start)
+    }
+
     pub fn get_piece_hash(&self, piece_index: usize) -> String {
         let bytes = self.info.pieces.chunks_exact(20).nth(piece_index).unwrap();
 
-        hex::encode(&bytes)
+        hex::encode(bytes)
+    }
+
+    /// The files of the torrent with their position in the piece data.
+    ///
+    /// Single-file torrents have one entry named after `info.name`, multi-file
+    /// torrents have their entries nested in a directory named after `info.name`.
+    pub fn get_file_layout(&self) -> Vec<FileLayout> {
+        let files = match &self.info.files {
+            Some(files) => files,
+            None => {
+                return vec![FileLayout {
+                    path: PathBuf::from(sanitize_path_component(&self.info.name)),
+                    length: self.get_total_length(),
+                    offset: 0,
+                }]
+            }
+        };
+
+        let root = PathBuf::from(sanitize_path_component(&self.info.name));
+        let mut offset = 0;
+
+        files
+            .iter()
+            .map(|file| {
+                let path = file.path.iter().fold(root.clone(), |path, c| {
+                    path.join(sanitize_path_component(c))
+                });
+
+                let layout = FileLayout {
+                    path,
+                    length: file.length,
+                    offset,
+                };
+                offset += file.length;
+
+                layout
+            })
+            .collect()
+    }
+}
+
+/// Prevents a path component coming from the torrent from escaping the
+/// download directory.
+fn sanitize_path_component(component: &str) -> String {
+    match component {
+        "" | "." | ".." => "_".to_string(),
+        c => c.replace(['/', '\\'], "_"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn multi_file_info(
+        lengths: &[usize],
+        piece_length: usize,
+        no_of_pieces: usize,
+    ) -> TorrentFileInfo {
+        TorrentFileInfo {
+            name: "sample".to_string(),
+            piece_length,
+            length: None,
+            files: Some(
+                lengths
+                    .iter()
+                    .enumerate()
+                    .map(|(i, length)| TorrentFileEntry {
+                        length: *length,
+                        path: vec!["dir".to_string(), format!("{}.txt", i)],
+                    })
+                    .collect(),
+            ),
+            pieces: vec![0; 20 * no_of_pieces],
+        }
+    }
+
+    fn from_info(info: &TorrentFileInfo) -> anyhow::Result<TorrentFile> {
+        TorrentFile::from_info_bytes(&[], serde_bencode::to_bytes(info).unwrap())
+    }
+
+    #[test]
+    fn multi_file_torrents_are_nested_under_their_name() {
+        let torrent = from_info(&multi_file_info(&[10, 0, 25], 16, 3)).unwrap();
+
+        assert!(torrent.is_multi_file());
+        assert_eq!(torrent.get_total_length(), 35);
+        assert_eq!(torrent.get_piece_length(2), 3);
+        assert_eq!(
+            torrent.get_file_layout(),
+            vec![
+                FileLayout {
+                    path: PathBuf::from("sample/dir/0.txt"),
+                    length: 10,
+                    offset: 0,
+                },
+                FileLayout {
+                    path: PathBuf::from("sample/dir/1.txt"),
+                    length: 0,
+                    offset: 10,
+                },
+                FileLayout {
+                    path: PathBuf::from("sample/dir/2.txt"),
+                    length: 25,
+                    offset: 10,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn path_components_cannot_escape_the_download_directory() {
+        let mut info = multi_file_info(&[10], 16, 1);
+        info.name = "..".to_string();
+        info.files.as_mut().unwrap()[0].path = vec!["..".to_string(), "a/b".to_string()];
+
+        let torrent = from_info(&info).unwrap();
+
+        assert_eq!(
+            torrent.get_file_layout()[0].path,
+            PathBuf::from("_").join("_").join("a_b")
+        );
+    }
+
+    #[test]
+    fn rejects_both_or_neither_of_length_and_files() {
+        let mut info = multi_file_info(&[10], 16, 1);
+        info.length = Some(10);
+        assert!(from_info(&info).is_err());
+
+        info.length = None;
+        info.files = None;
+        assert!(from_info(&info).is_err());
+    }
+
+    #[test]
+    fn rejects_a_file_without_a_path() {
+        let mut info = multi_file_info(&[10, 5], 16, 1);
+        info.files.as_mut().unwrap()[1].path = vec![];
+
+        let err = from_info(&info).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Expected every file to have a path but found a file of 5 bytes without one"
+        );
+    }
+
+    #[test]
+    fn rejects_a_zero_piece_length() {
+        let err = from_info(&multi_file_info(&[10], 0, 1)).unwrap_err();
+
+        assert_eq!(err.to_string(), "Expected a piece length greater than 0");
+    }
+
+    #[test]
+    fn rejects_a_piece_count_not_matching_the_length() {
+        let err = from_info(&multi_file_info(&[10, 10], 16, 1)).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Expected 2 piece hashes for 20 bytes in pieces of 16 bytes but found 1"
+        );
+    }
+
+    #[test]
+    fn rejects_pieces_which_are_not_a_multiple_of_20() {
+        let mut info = multi_file_info(&[10], 16, 1);
+        info.pieces.pop();
+
+        assert!(from_info(&info).is_err());
+    }
+
+    fn layout(lengths: &[usize]) -> Vec<FileLayout> {
+        let mut offset = 0;
+
+        lengths
+            .iter()
+            .enumerate()
+            .map(|(i, length)| {
+                let file = FileLayout {
+                    path: PathBuf::from(format!("{}", i)),
+                    length: *length,
+                    offset,
+                };
+                offset += length;
+                file
+            })
+            .collect()
+    }
+
+    fn segment(
+        file_index: usize,
+        file_offset: usize,
+        data_offset: usize,
+        length: usize,
+    ) -> FileSegment {
+        FileSegment {
+            file_index,
+            file_offset,
+            data_offset,
+            length,
+        }
+    }
+
+    #[test]
+    fn range_within_a_file() {
+        assert_eq!(
+            get_file_segments(&layout(&[10, 20]), 12, 5),
+            vec![segment(1, 2, 0, 5)]
+        );
+    }
+
+    #[test]
+    fn range_straddling_files() {
+        assert_eq!(
+            get_file_segments(&layout(&[10, 5, 20]), 8, 10),
+            vec![
+                segment(0, 8, 0, 2),
+                segment(1, 0, 2, 5),
+                segment(2, 0, 7, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_files_have_no_segments() {
+        assert_eq!(
+            get_file_segments(&layout(&[10, 0, 10]), 5, 10),
+            vec![segment(0, 5, 0, 5), segment(2, 0, 5, 5)]
+        );
+    }
+
+    #[test]
+    fn range_ending_at_a_file_boundary() {
+        assert_eq!(
+            get_file_segments(&layout(&[10, 10]), 0, 10),
+            vec![segment(0, 0, 0, 10)]
+        );
+    }
+
+    #[test]
+    fn range_past_the_last_file() {
+        assert_eq!(get_file_segments(&layout(&[10]), 10, 5), vec![]);
     }
 }
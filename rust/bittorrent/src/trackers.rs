@@ -1,8 +1,52 @@
+use std::collections::BTreeMap;
+use std::fmt;
 use std::mem::size_of;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use serde_bytes::{ByteBuf, Bytes};
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::udp_tracker;
+
+/// How long an HTTP tracker gets to answer an announce or a scrape. UDP
+/// trackers are bounded by their retransmission schedule instead.
+pub const TRACKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The client of HTTP tracker requests, which otherwise never time out.
+fn http_client() -> &'static reqwest::Client {
+    static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+
+    HTTP_CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .timeout(TRACKER_TIMEOUT)
+            .build()
+            .expect("Could not create the HTTP client")
+    })
+}
+
+/// The state change an announce reports, regular announces have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnounceEvent {
+    Started,
+    Completed,
+    Stopped,
+}
+
+impl AnnounceEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnnounceEvent::Started => "started",
+            AnnounceEvent::Completed => "completed",
+            AnnounceEvent::Stopped => "stopped",
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoverPeersRequest {
@@ -14,11 +58,26 @@ pub struct DiscoverPeersRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: i8,
+
+    /// The `tracker id` the tracker sent with a previous response.
+    #[serde(default)]
+    pub tracker_id: Option<String>,
+
+    #[serde(default)]
+    pub event: Option<AnnounceEvent>,
+
+    /// The number of peers wanted, the tracker decides when not set.
+    #[serde(default)]
+    pub numwant: Option<usize>,
+
+    /// A random value identifying us to the tracker across IP changes.
+    #[serde(default)]
+    pub key: Option<u32>,
 }
 
 impl DiscoverPeersRequest {
     pub fn get_url(&self) -> String {
-        let query = vec![
+        let mut query = vec![
             ("peer_id", self.peer_id.to_string()),
             ("port", self.port.to_string()),
             ("uploaded", self.uploaded.to_string()),
@@ -26,55 +85,349 @@ impl DiscoverPeersRequest {
             ("left", self.left.to_string()),
             ("compact", self.compact.to_string()),
         ];
-
-        let info_hash: String = self
-            .info_hash
-            .iter()
-            .map(|v| format!("%{}", hex::encode(&[*v])))
-            .collect();
+        if let Some(tracker_id) = &self.tracker_id {
+            query.push(("trackerid", tracker_id.to_string()));
+        }
+        if let Some(event) = &self.event {
+            query.push(("event", event.as_str().to_string()));
+        }
+        if let Some(numwant) = self.numwant {
+            query.push(("numwant", numwant.to_string()));
+        }
+        if let Some(key) = self.key {
+            query.push(("key", format!("{:08x}", key)));
+        }
 
         format!(
             "{}?{}&info_hash={}",
             &self.announce_url,
             serde_urlencoded::to_string(&query).unwrap(),
-            info_hash
+            percent_encode(&self.info_hash)
         )
     }
+
+    /// Announces to the tracker, over UDP (BEP 15) for `udp://` URLs and
+    /// over HTTP otherwise.
+    pub async fn send(&self) -> anyhow::Result<DiscoverPeersResponse> {
+        if self.announce_url.starts_with("udp://") {
+            return udp_tracker::announce(self).await;
+        }
+
+        let bytes = http_client()
+            .get(self.get_url())
+            .send()
+            .await?
+            .bytes()
+            .await?;
+
+        Ok(DiscoverPeersResponse::from_bencoded_bytes(&bytes[..])?)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct DiscoverPeersResponse {
+/// Percent-encodes every byte, info hashes are raw bytes.
+fn percent_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|v| format!("%{}", hex::encode([*v])))
+        .collect()
+}
+
+/// The scrape URL of a tracker: by convention, the last path component of
+/// the announce URL with `announce` replaced by `scrape`. Trackers whose
+/// announce URL does not follow the convention do not support scraping.
+/// UDP trackers are scraped at their announce URL.
+pub fn get_scrape_url(announce_url: &str) -> Option<String> {
+    if announce_url.starts_with("udp://") {
+        return Some(announce_url.to_string());
+    }
+
+    // the query may contain slashes of its own
+    let query_start = announce_url.find('?').unwrap_or(announce_url.len());
+    let (path, query) = announce_url.split_at(query_start);
+    let slash = path.rfind('/')?;
+    let last_component = &path[slash + 1..];
+
+    last_component
+        .strip_prefix("announce")
+        .map(|rest| format!("{}scrape{}{}", &path[..=slash], rest, query))
+}
+
+#[derive(Debug, Clone)]
+pub struct ScrapeRequest {
+    pub scrape_url: String,
+    pub info_hashes: Vec<[u8; 20]>,
+}
+
+impl ScrapeRequest {
+    pub fn get_url(&self) -> String {
+        let info_hashes: Vec<String> = self
+            .info_hashes
+            .iter()
+            .map(|info_hash| format!("info_hash={}", percent_encode(info_hash)))
+            .collect();
+        let separator = if self.scrape_url.contains('?') {
+            '&'
+        } else {
+            '?'
+        };
+
+        format!("{}{}{}", self.scrape_url, separator, info_hashes.join("&"))
+    }
+
+    /// Scrapes the tracker, over UDP (BEP 15) for `udp://` URLs and over
+    /// HTTP otherwise.
+    pub async fn send(&self) -> anyhow::Result<ScrapeResponse> {
+        if self.scrape_url.starts_with("udp://") {
+            return udp_tracker::scrape(self).await;
+        }
+
+        let bytes = http_client()
+            .get(self.get_url())
+            .send()
+            .await?
+            .bytes()
+            .await?;
+
+        Ok(ScrapeResponse::from_bencoded_bytes(&bytes[..])?)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScrapeResponse {
+    #[serde(
+        default,
+        rename = "failure reason",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub failure_reason: Option<String>,
+
+    /// The stats by info hash, trackers leave out the torrents they do not
+    /// know about.
+    #[serde(default)]
+    pub files: BTreeMap<ByteBuf, ScrapeStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScrapeStats {
+    /// The number of seeders.
     pub complete: usize,
+
+    /// The number of times the torrent was downloaded completely.
+    pub downloaded: usize,
+
+    /// The number of leechers.
     pub incomplete: usize,
-    pub interval: usize,
 
-    #[serde(rename = "min interval")]
-    pub min_interval: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl ScrapeResponse {
+    /// Parses a scrape response, turning a `failure reason` into an error.
+    pub fn from_bencoded_bytes(bytes: &[u8]) -> Result<ScrapeResponse, TrackerError> {
+        let response: ScrapeResponse = serde_bencode::from_bytes(bytes)?;
+
+        match response.failure_reason {
+            Some(reason) => Err(TrackerError::Failure(reason)),
+            None => Ok(response),
+        }
+    }
+
+    pub fn get_stats(&self, info_hash: &[u8; 20]) -> Option<&ScrapeStats> {
+        self.files.get(Bytes::new(info_hash))
+    }
+}
 
-    #[serde(with = "serde_bytes")]
-    pub peers: Vec<u8>,
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    /// The tracker answered with a `failure reason`.
+    #[error("tracker failed: {0}")]
+    Failure(String),
+    #[error("invalid tracker response: {0}")]
+    InvalidResponse(#[from] serde_bencode::Error),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiscoverPeersResponse {
+    /// Why the request failed, no other keys are present when it is set.
+    #[serde(
+        default,
+        rename = "failure reason",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub failure_reason: Option<String>,
+
+    /// Like a failure reason, but the response is processed normally.
+    #[serde(
+        default,
+        rename = "warning message",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub warning_message: Option<String>,
+
+    /// The number of seeders.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub complete: Option<usize>,
+
+    /// The number of leechers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub incomplete: Option<usize>,
+
+    /// The number of seconds to wait before the next regular announce.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<usize>,
+
+    /// Announces must not be sent more often than this, in seconds.
+    #[serde(
+        default,
+        rename = "min interval",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub min_interval: Option<usize>,
+
+    /// To be sent back as `trackerid` with the next announces.
+    #[serde(
+        default,
+        rename = "tracker id",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tracker_id: Option<String>,
+
+    #[serde(default)]
+    pub peers: TrackerPeers,
+
+    /// IPv6 peers, 18 bytes each: the address followed by the port (BEP 7).
+    #[serde(default, with = "serde_bytes")]
+    pub peers6: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum TrackerPeers {
+    /// IPv4 peers, 6 bytes each: the address followed by the port (BEP 23).
+    Compact(#[serde(with = "serde_bytes")] Vec<u8>),
+    /// The original format, a dictionary per peer.
+    Dictionaries(Vec<TrackerPeerEntry>),
+}
+
+impl Default for TrackerPeers {
+    fn default() -> Self {
+        TrackerPeers::Compact(vec![])
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackerPeerEntry {
+    #[serde(default, rename = "peer id", skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<serde_bytes::ByteBuf>,
+
+    /// An IPv4 or IPv6 address, or a host name.
+    pub ip: String,
+
+    pub port: u16,
+}
+
+/// How a peer can be reached, trackers may give host names instead of IPs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerHost {
+    Ip(IpAddr),
+    Name(String),
+}
+
+/// A peer returned by a tracker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub host: PeerHost,
+    pub port: u16,
+
+    /// The peer id the tracker knows the peer by, if it sent one.
+    pub peer_id: Option<[u8; 20]>,
+}
+
+impl Peer {
+    /// Looks up the address of peers given by host name.
+    pub async fn resolve(&self) -> anyhow::Result<SocketAddr> {
+        match &self.host {
+            PeerHost::Ip(ip) => Ok(SocketAddr::new(*ip, self.port)),
+            PeerHost::Name(name) => tokio::net::lookup_host((name.as_str(), self.port))
+                .await?
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Could not resolve peer {}", self)),
+        }
+    }
+
+    pub fn has_address_of(&self, other: &Peer) -> bool {
+        self.host == other.host && self.port == other.port
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Self {
+        Peer {
+            host: PeerHost::Ip(addr.ip()),
+            port: addr.port(),
+            peer_id: None,
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.host {
+            PeerHost::Ip(ip) => write!(f, "{}", SocketAddr::new(*ip, self.port)),
+            PeerHost::Name(name) => write!(f, "{}:{}", name, self.port),
+        }
+    }
 }
 
 impl DiscoverPeersResponse {
-    pub fn from_bencoded_bytes(
-        bytes: &[u8],
-    ) -> Result<DiscoverPeersResponse, serde_bencode::Error> {
-        match serde_bencode::from_bytes(bytes) {
-            Ok(res) => Ok(res),
-            Err(err) => Err(err),
+    /// Parses a tracker response, turning a `failure reason` into an error.
+    pub fn from_bencoded_bytes(bytes: &[u8]) -> Result<DiscoverPeersResponse, TrackerError> {
+        let response: DiscoverPeersResponse = serde_bencode::from_bytes(bytes)?;
+
+        match response.failure_reason {
+            Some(reason) => Err(TrackerError::Failure(reason)),
+            None => Ok(response),
         }
     }
 
-    pub fn parse_peers(&self) -> Vec<SocketAddr> {
-        self.peers
-            .chunks_exact(6)
-            .map(|ch| {
-                let ip = Ipv4Addr::new(ch[0], ch[1], ch[2], ch[3]);
-                let port = u16::from_be_bytes([ch[4], ch[5]]);
+    /// The peers from both `peers`, in either format, and `peers6`. A
+    /// truncated entry at the end of a compact list is ignored.
+    pub fn parse_peers(&self) -> Vec<Peer> {
+        let peers: Vec<Peer> = match &self.peers {
+            TrackerPeers::Compact(bytes) => bytes
+                .chunks_exact(6)
+                .map(|ch| {
+                    let ip = Ipv4Addr::new(ch[0], ch[1], ch[2], ch[3]);
+                    let port = u16::from_be_bytes([ch[4], ch[5]]);
+
+                    Peer::from(SocketAddr::new(IpAddr::V4(ip), port))
+                })
+                .collect(),
+            TrackerPeers::Dictionaries(entries) => entries
+                .iter()
+                .map(|entry| Peer {
+                    host: match entry.ip.parse() {
+                        Ok(ip) => PeerHost::Ip(ip),
+                        Err(_) => PeerHost::Name(entry.ip.clone()),
+                    },
+                    port: entry.port,
+                    peer_id: entry
+                        .peer_id
+                        .as_ref()
+                        .and_then(|id| id.as_slice().try_into().ok()),
+                })
+                .collect(),
+        };
 
-                SocketAddr::new(IpAddr::V4(ip), port)
-            })
-            .collect()
+        let peers6 = self.peers6.chunks_exact(18).map(|ch| {
+            let ip: [u8; 16] = ch[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([ch[16], ch[17]]);
+
+            Peer::from(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port))
+        });
+
+        peers.into_iter().chain(peers6).collect()
     }
 }
 
@@ -84,7 +437,7 @@ pub struct PeerHandshake {
     pub length: u8,
     /// the string BitTorrent protocol (19 bytes)
     pub bittorrent: [u8; 19],
-    /// eight reserved bytes, which are all set to zero (8 bytes)
+    /// eight reserved bytes used to advertise protocol extensions (8 bytes)
     pub reserved: [u8; 8],
     /// sha1 infohash (20 bytes)
     pub info_hash: [u8; 20],
@@ -93,15 +446,21 @@ pub struct PeerHandshake {
     pub peer_id: [u8; 20],
 }
 
+/// The protocol string every handshake starts with.
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+
 impl TryFrom<[u8; size_of::<PeerHandshake>()]> for PeerHandshake {
-    type Error = ();
+    type Error = anyhow::Error;
 
     fn try_from(value: [u8; size_of::<PeerHandshake>()]) -> Result<Self, Self::Error> {
         let mut i: usize = 0;
-        let length = value[0] as u8;
+        let length = value[0];
         i += 1;
 
-        let bittorrent: [u8; 19] = value[i..i + length as usize].try_into().unwrap();
+        if length as usize != PROTOCOL.len() || &value[i..i + PROTOCOL.len()] != PROTOCOL {
+            anyhow::bail!("Peer does not speak the BitTorrent protocol");
+        }
+        let bittorrent: [u8; 19] = value[i..i + 19].try_into().unwrap();
         i += 19;
 
         let reserved: [u8; 8] = value[i..i + 8].try_into().unwrap();
@@ -122,26 +481,257 @@ impl TryFrom<[u8; size_of::<PeerHandshake>()]> for PeerHandshake {
     }
 }
 
+/// The reserved byte and bit signalling support for the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
 impl PeerHandshake {
     pub fn from(info_hash: [u8; 20], peer_id: String) -> PeerHandshake {
+        let mut reserved = [0; 8];
+        reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+
         PeerHandshake {
-            length: 19,
-            bittorrent: "BitTorrent protocol".as_bytes().try_into().unwrap(),
-            reserved: [0; 8],
+            length: PROTOCOL.len() as u8,
+            bittorrent: *PROTOCOL,
+            reserved,
             info_hash,
             peer_id: peer_id.as_bytes().try_into().unwrap(),
         }
     }
 
+    pub async fn read_from_stream(stream: &mut TcpStream) -> anyhow::Result<PeerHandshake> {
+        let mut buf = [0; size_of::<PeerHandshake>()];
+        stream.read_exact(&mut buf).await?;
+
+        buf.try_into()
+    }
+
+    /// The same handshake without the extension protocol bit, for connections
+    /// on which we do not send an extension handshake.
+    pub fn without_extensions(mut self) -> PeerHandshake {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] &= !EXTENSION_PROTOCOL_BIT;
+        self
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+    }
+
     pub async fn write_to_stream(&self, stream: &mut TcpStream) -> anyhow::Result<()> {
         stream.write_u8(self.length).await?;
-        stream.write(&self.bittorrent).await?;
-        stream.write(&self.reserved).await?;
-        stream.write(&self.info_hash).await?;
-        stream.write(&self.peer_id).await?;
+        stream.write_all(&self.bittorrent).await?;
+        stream.write_all(&self.reserved).await?;
+        stream.write_all(&self.info_hash).await?;
+        stream.write_all(&self.peer_id).await?;
 
         stream.flush().await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response with `interval` and the given `peers` and `peers6` values,
+    /// which must be bencoded already.
+    fn response_with_peers(peers: &[u8], peers6: &[u8]) -> DiscoverPeersResponse {
+        let mut bytes = b"d8:intervali1800e5:peers".to_vec();
+        bytes.extend_from_slice(peers);
+        bytes.extend_from_slice(b"6:peers6");
+        bytes.extend_from_slice(peers6);
+        bytes.push(b'e');
+
+        DiscoverPeersResponse::from_bencoded_bytes(&bytes).unwrap()
+    }
+
+    fn peer(addr: &str) -> Peer {
+        Peer::from(addr.parse::<SocketAddr>().unwrap())
+    }
+
+    #[test]
+    fn parses_compact_peers() {
+        let response = response_with_peers(
+            b"12:\x0a\x00\x00\x01\x1a\xe1\x7f\x00\x00\x01\x1a\xe2",
+            b"0:",
+        );
+
+        assert_eq!(
+            response.parse_peers(),
+            vec![peer("10.0.0.1:6881"), peer("127.0.0.1:6882")]
+        );
+    }
+
+    #[test]
+    fn parses_dictionary_peers() {
+        let response = response_with_peers(
+            b"ld2:ip8:10.0.0.17:peer id20:-XX0001-0123456789ab4:porti6881eed2:ip16:peer.example.com4:porti6882eed2:ip3:::14:porti6883eee",
+            b"0:",
+        );
+
+        assert_eq!(
+            response.parse_peers(),
+            vec![
+                Peer {
+                    peer_id: Some(*b"-XX0001-0123456789ab"),
+                    ..peer("10.0.0.1:6881")
+                },
+                Peer {
+                    host: PeerHost::Name("peer.example.com".to_string()),
+                    port: 6882,
+                    peer_id: None,
+                },
+                peer("[::1]:6883"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_ipv6_peers() {
+        let mut peers6 = b"36:".to_vec();
+        peers6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        peers6.extend_from_slice(&6881u16.to_be_bytes());
+        peers6.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        peers6.extend_from_slice(&6882u16.to_be_bytes());
+
+        let response = response_with_peers(b"6:\x0a\x00\x00\x01\x1a\xe1", &peers6);
+
+        assert_eq!(
+            response.parse_peers(),
+            vec![
+                peer("10.0.0.1:6881"),
+                peer("[::1]:6881"),
+                peer("[2001:db8::2]:6882"),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_a_truncated_compact_peer() {
+        let mut peers6 = b"20:".to_vec();
+        peers6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        peers6.extend_from_slice(&[0x1a, 0xe1, 0, 0]);
+
+        let response = response_with_peers(b"8:\x0a\x00\x00\x01\x1a\xe1\x0a\x00", &peers6);
+
+        assert_eq!(
+            response.parse_peers(),
+            vec![peer("10.0.0.1:6881"), peer("[::1]:6881")]
+        );
+    }
+
+    #[test]
+    fn failure_reason_becomes_the_error() {
+        let err =
+            DiscoverPeersResponse::from_bencoded_bytes(b"d14:failure reason15:unknown torrente")
+                .unwrap_err();
+
+        match err {
+            TrackerError::Failure(reason) => assert_eq!(reason, "unknown torrent"),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn optional_fields_may_be_missing() {
+        let response =
+            DiscoverPeersResponse::from_bencoded_bytes(b"d5:peers6:\x0a\x00\x00\x01\x1a\xe1e")
+                .unwrap();
+
+        assert_eq!(response.interval, None);
+        assert_eq!(response.min_interval, None);
+        assert_eq!(response.complete, None);
+        assert_eq!(response.incomplete, None);
+        assert_eq!(response.parse_peers(), vec![peer("10.0.0.1:6881")]);
+    }
+
+    #[test]
+    fn warning_message_keeps_the_response() {
+        let response = DiscoverPeersResponse::from_bencoded_bytes(
+            b"d8:completei3e10:incompletei2e8:intervali900e12:min intervali300e5:peers0:15:warning message7:be nicee",
+        )
+        .unwrap();
+
+        assert_eq!(response.warning_message.as_deref(), Some("be nice"));
+        assert_eq!(response.interval, Some(900));
+        assert_eq!(response.min_interval, Some(300));
+        assert_eq!(response.complete, Some(3));
+        assert_eq!(response.incomplete, Some(2));
+        assert!(response.parse_peers().is_empty());
+    }
+
+    #[test]
+    fn scrape_url_replaces_the_last_announce_component() {
+        let cases = [
+            (
+                "http://example.com/announce",
+                Some("http://example.com/scrape"),
+            ),
+            (
+                "http://example.com/x/announce",
+                Some("http://example.com/x/scrape"),
+            ),
+            (
+                "http://example.com/announce.php",
+                Some("http://example.com/scrape.php"),
+            ),
+            (
+                "http://example.com/announce?x2%0644",
+                Some("http://example.com/scrape?x2%0644"),
+            ),
+            (
+                "http://example.com/announce?path=/a/b",
+                Some("http://example.com/scrape?path=/a/b"),
+            ),
+            (
+                "http://example.com/announce/x/announce",
+                Some("http://example.com/announce/x/scrape"),
+            ),
+            ("http://example.com/a", None),
+            ("http://example.com/announce/x", None),
+            ("http://example.com/x%064announce", None),
+            ("http://example.com/a?x=/announce", None),
+            (
+                "udp://tracker.example.com:80",
+                Some("udp://tracker.example.com:80"),
+            ),
+        ];
+
+        for (announce_url, scrape_url) in cases {
+            assert_eq!(
+                get_scrape_url(announce_url).as_deref(),
+                scrape_url,
+                "{}",
+                announce_url
+            );
+        }
+    }
+
+    #[test]
+    fn scrape_failure_reason_becomes_the_error() {
+        let err =
+            ScrapeResponse::from_bencoded_bytes(b"d14:failure reason9:forbiddene").unwrap_err();
+
+        match err {
+            TrackerError::Failure(reason) => assert_eq!(reason, "forbidden"),
+            err => panic!("unexpected error {}", err),
+        }
+    }
+
+    #[test]
+    fn scrape_stats_are_found_by_info_hash() {
+        let mut bytes = b"d5:filesd20:".to_vec();
+        bytes.extend_from_slice(&[1; 20]);
+        bytes.extend_from_slice(b"d8:completei5e10:downloadedi6e10:incompletei7eeee");
+
+        let response = ScrapeResponse::from_bencoded_bytes(&bytes).unwrap();
+
+        let stats = response.get_stats(&[1; 20]).unwrap();
+        assert_eq!(
+            (stats.complete, stats.downloaded, stats.incomplete),
+            (5, 6, 7)
+        );
+        assert!(response.get_stats(&[2; 20]).is_none());
+    }
+}
@@ -0,0 +1,575 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+use serde_bytes::ByteBuf;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::random::random_u32;
+use crate::trackers::{
+    AnnounceEvent, DiscoverPeersRequest, DiscoverPeersResponse, ScrapeRequest, ScrapeResponse,
+    ScrapeStats, TrackerError, TrackerPeers,
+};
+
+/// The magic constant starting every connect request (BEP 15).
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+/// Trackers accept a connection id for one minute after handing it out.
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Requests are retransmitted after 5 * 2 ^ n seconds, so a dead tracker is
+/// given up on after 5 + 10 + 20 = 35 seconds. BEP 15 uses 15 * 2 ^ n up to
+/// n = 8, which would leave us waiting on it for over an hour.
+const BASE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RETRANSMISSIONS: u32 = 2;
+
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// The most info hashes a scrape request can carry.
+const MAX_SCRAPE_HASHES: usize = 74;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Connect = 0,
+    Announce = 1,
+    Scrape = 2,
+    Error = 3,
+}
+
+/// Connection ids by tracker address, with the time they were received.
+fn connection_ids() -> &'static Mutex<HashMap<SocketAddr, (u64, Instant)>> {
+    static CONNECTION_IDS: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+
+    CONNECTION_IDS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// A socket talking to a single `udp://` tracker.
+struct UdpTracker {
+    addr: SocketAddr,
+    socket: UdpSocket,
+
+    /// How long the first attempt of a request waits for a response.
+    base_timeout: Duration,
+}
+
+impl UdpTracker {
+    async fn connect(url: &str) -> anyhow::Result<UdpTracker> {
+        let url = Url::parse(url)?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("Tracker URL {} has no host", url))?;
+        let port = url
+            .port()
+            .ok_or_else(|| anyhow::anyhow!("Tracker URL {} has no port", url))?;
+
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("Could not resolve tracker {}", host))?;
+        let socket = if addr.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        socket.connect(addr).await?;
+
+        Ok(UdpTracker {
+            addr,
+            socket,
+            base_timeout: BASE_TIMEOUT,
+        })
+    }
+
+    /// Sends a request built for a connection id, retransmitting it with
+    /// exponential backoff, and returns the body of the response following
+    /// the action and transaction id. A new connection id is obtained
+    /// whenever the cached one expired, including between retransmissions.
+    async fn request(&self, action: Action, body: &[u8]) -> anyhow::Result<Vec<u8>> {
+        for n in 0..=MAX_RETRANSMISSIONS {
+            let wait = self.base_timeout * 2u32.pow(n);
+
+            let connection_id = match self.cached_connection_id() {
+                Some(connection_id) => connection_id,
+                None => match self
+                    .try_request(PROTOCOL_ID, Action::Connect, &[], wait)
+                    .await?
+                {
+                    Some(response) => {
+                        let connection_id = read_u64(&response, 0)?;
+                        connection_ids()
+                            .lock()
+                            .unwrap()
+                            .insert(self.addr, (connection_id, Instant::now()));
+                        connection_id
+                    }
+                    None => continue,
+                },
+            };
+
+            if let Some(response) = self.try_request(connection_id, action, body, wait).await? {
+                return Ok(response);
+            }
+        }
+
+        anyhow::bail!("Tracker {} did not respond", self.addr)
+    }
+
+    fn cached_connection_id(&self) -> Option<u64> {
+        let mut cache = connection_ids().lock().unwrap();
+
+        match cache.get(&self.addr) {
+            Some((connection_id, received)) if received.elapsed() < CONNECTION_ID_LIFETIME => {
+                Some(*connection_id)
+            }
+            Some(_) => {
+                cache.remove(&self.addr);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Sends a single request, returning `None` if no matching response
+    /// arrived in time. Responses to other transactions are ignored.
+    async fn try_request(
+        &self,
+        connection_id: u64,
+        action: Action,
+        body: &[u8],
+        wait: Duration,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let transaction_id = random_u32();
+
+        let mut packet = Vec::with_capacity(16 + body.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&(action as u32).to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        self.socket.send(&packet).await?;
+
+        let deadline = Instant::now() + wait;
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let length = match timeout(remaining, self.socket.recv(&mut buf)).await {
+                Ok(length) => length?,
+                Err(_) => return Ok(None),
+            };
+            let response = &buf[..length];
+
+            if length < 8 || read_u32(response, 4)? != transaction_id {
+                continue;
+            }
+
+            let response_action = read_u32(response, 0)?;
+            if response_action == Action::Error as u32 {
+                let reason = String::from_utf8_lossy(&response[8..]).to_string();
+                return Err(TrackerError::Failure(reason).into());
+            }
+            if response_action != action as u32 {
+                anyhow::bail!(
+                    "Tracker {} answered action {} with action {}",
+                    self.addr,
+                    action as u32,
+                    response_action
+                );
+            }
+
+            return Ok(Some(response[8..].to_vec()));
+        }
+    }
+
+    /// Announces to the tracker, its peers are in the address family of
+    /// the tracker.
+    async fn announce(
+        &self,
+        request: &DiscoverPeersRequest,
+    ) -> anyhow::Result<DiscoverPeersResponse> {
+        let mut body = Vec::with_capacity(82);
+        body.extend_from_slice(&request.info_hash);
+        body.extend_from_slice(request.peer_id.as_bytes());
+        body.extend_from_slice(&(request.downloaded as u64).to_be_bytes());
+        body.extend_from_slice(&(request.left as u64).to_be_bytes());
+        body.extend_from_slice(&(request.uploaded as u64).to_be_bytes());
+        let event: u32 = match request.event {
+            None => 0,
+            Some(AnnounceEvent::Completed) => 1,
+            Some(AnnounceEvent::Started) => 2,
+            Some(AnnounceEvent::Stopped) => 3,
+        };
+        body.extend_from_slice(&event.to_be_bytes());
+        // IP address: the one the request comes from
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&request.key.unwrap_or(0).to_be_bytes());
+        // num_want: -1 for the tracker's default
+        let numwant = request
+            .numwant
+            .map_or(-1, |n| n.min(i32::MAX as usize) as i32);
+        body.extend_from_slice(&numwant.to_be_bytes());
+        body.extend_from_slice(&(request.port as u16).to_be_bytes());
+
+        let response = self.request(Action::Announce, &body).await?;
+
+        let mut discovered = DiscoverPeersResponse {
+            interval: Some(read_u32(&response, 0)? as usize),
+            incomplete: Some(read_u32(&response, 4)? as usize),
+            complete: Some(read_u32(&response, 8)? as usize),
+            ..Default::default()
+        };
+        // trackers reached over IPv6 answer with IPv6 peers
+        if self.addr.is_ipv6() {
+            discovered.peers6 = response[12..].to_vec();
+        } else {
+            discovered.peers = TrackerPeers::Compact(response[12..].to_vec());
+        }
+
+        Ok(discovered)
+    }
+
+    /// Scrapes the tracker. The stats come back in the order of the info
+    /// hashes, requests with more hashes than fit a packet are split.
+    async fn scrape(&self, request: &ScrapeRequest) -> anyhow::Result<ScrapeResponse> {
+        let mut scraped = ScrapeResponse::default();
+
+        for info_hashes in request.info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let body = info_hashes.concat();
+            let response = self.request(Action::Scrape, &body).await?;
+
+            for (i, info_hash) in info_hashes.iter().enumerate() {
+                let stats = ScrapeStats {
+                    complete: read_u32(&response, i * 12)? as usize,
+                    downloaded: read_u32(&response, i * 12 + 4)? as usize,
+                    incomplete: read_u32(&response, i * 12 + 8)? as usize,
+                    name: None,
+                };
+                scraped
+                    .files
+                    .insert(ByteBuf::from(info_hash.to_vec()), stats);
+            }
+        }
+
+        Ok(scraped)
+    }
+}
+
+/// Announces to a `udp://` tracker, see [`DiscoverPeersRequest::send`].
+pub async fn announce(request: &DiscoverPeersRequest) -> anyhow::Result<DiscoverPeersResponse> {
+    UdpTracker::connect(&request.announce_url)
+        .await?
+        .announce(request)
+        .await
+}
+
+/// Scrapes a `udp://` tracker, see [`ScrapeRequest::send`].
+pub async fn scrape(request: &ScrapeRequest) -> anyhow::Result<ScrapeResponse> {
+    UdpTracker::connect(&request.scrape_url)
+        .await?
+        .scrape(request)
+        .await
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> anyhow::Result<u32> {
+    bytes
+        .get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| anyhow::anyhow!("Tracker response is too short"))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> anyhow::Result<u64> {
+    bytes
+        .get(offset..offset + 8)
+        .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| anyhow::anyhow!("Tracker response is too short"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::trackers::Peer;
+
+    /// A tracker on a local socket handing out the connection ids 1, 2, ...
+    /// It ignores the first `dropped` packets, and answers announces and
+    /// scrapes with an error when given a `failure`.
+    struct FakeTracker {
+        url: String,
+
+        /// The connection id and action of every packet received, including
+        /// the ignored ones.
+        received: Arc<Mutex<Vec<(u64, u32)>>>,
+    }
+
+    impl FakeTracker {
+        async fn start(dropped: usize, failure: Option<&'static str>) -> FakeTracker {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+            let received = Arc::new(Mutex::new(vec![]));
+
+            let log = received.clone();
+            tokio::spawn(async move {
+                let mut buf = vec![0; MAX_PACKET_SIZE];
+                let mut next_connection_id = 1u64;
+
+                loop {
+                    let (length, from) = socket.recv_from(&mut buf).await.unwrap();
+                    let packet = &buf[..length];
+                    let action = read_u32(packet, 8).unwrap();
+                    let transaction_id = &packet[12..16];
+
+                    let no_of_packets = {
+                        let mut log = log.lock().unwrap();
+                        log.push((read_u64(packet, 0).unwrap(), action));
+                        log.len()
+                    };
+                    if no_of_packets <= dropped {
+                        continue;
+                    }
+
+                    let mut response = vec![];
+                    match (action, failure) {
+                        (0, _) => {
+                            response.extend_from_slice(&0u32.to_be_bytes());
+                            response.extend_from_slice(transaction_id);
+                            response.extend_from_slice(&next_connection_id.to_be_bytes());
+                            next_connection_id += 1;
+                        }
+                        (_, Some(reason)) => {
+                            response.extend_from_slice(&3u32.to_be_bytes());
+                            response.extend_from_slice(transaction_id);
+                            response.extend_from_slice(reason.as_bytes());
+                        }
+                        (1, None) => {
+                            response.extend_from_slice(&1u32.to_be_bytes());
+                            response.extend_from_slice(transaction_id);
+                            for value in [1800u32, 2, 3] {
+                                response.extend_from_slice(&value.to_be_bytes());
+                            }
+                            response.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1]);
+                        }
+                        (_, None) => {
+                            response.extend_from_slice(&2u32.to_be_bytes());
+                            response.extend_from_slice(transaction_id);
+                            for _ in packet[16..].chunks(20) {
+                                for value in [5u32, 6, 7] {
+                                    response.extend_from_slice(&value.to_be_bytes());
+                                }
+                            }
+                        }
+                    }
+                    socket.send_to(&response, from).await.unwrap();
+                }
+            });
+
+            FakeTracker { url, received }
+        }
+
+        async fn connect(&self) -> UdpTracker {
+            let mut tracker = UdpTracker::connect(&self.url).await.unwrap();
+            tracker.base_timeout = Duration::from_millis(50);
+
+            tracker
+        }
+
+        fn received(&self) -> Vec<(u64, u32)> {
+            self.received.lock().unwrap().clone()
+        }
+    }
+
+    fn announce_request(url: &str) -> DiscoverPeersRequest {
+        DiscoverPeersRequest {
+            announce_url: url.to_string(),
+            info_hash: [1; 20],
+            peer_id: "00112233445566778899".to_string(),
+            port: 6881,
+            uploaded: 0,
+            downloaded: 0,
+            left: 100,
+            compact: 1,
+            tracker_id: None,
+            event: Some(AnnounceEvent::Started),
+            numwant: None,
+            key: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_connects_first() {
+        let fake = FakeTracker::start(0, None).await;
+        let tracker = fake.connect().await;
+
+        let response = tracker
+            .announce(&announce_request(&fake.url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(response.incomplete, Some(2));
+        assert_eq!(response.complete, Some(3));
+        assert_eq!(
+            response.parse_peers(),
+            vec![Peer::from("10.0.0.1:6881".parse::<SocketAddr>().unwrap())]
+        );
+        assert_eq!(
+            fake.received(),
+            vec![
+                (PROTOCOL_ID, Action::Connect as u32),
+                (1, Action::Announce as u32)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_returns_stats_by_info_hash() {
+        let fake = FakeTracker::start(0, None).await;
+        let tracker = fake.connect().await;
+        let request = ScrapeRequest {
+            scrape_url: fake.url.clone(),
+            info_hashes: vec![[1; 20], [2; 20]],
+        };
+
+        let response = tracker.scrape(&request).await.unwrap();
+
+        assert_eq!(response.files.len(), 2);
+        for info_hash in &request.info_hashes {
+            let stats = &response.files[&ByteBuf::from(info_hash.to_vec())];
+            assert_eq!(
+                (stats.complete, stats.downloaded, stats.incomplete),
+                (5, 6, 7)
+            );
+        }
+        assert_eq!(
+            fake.received(),
+            vec![
+                (PROTOCOL_ID, Action::Connect as u32),
+                (1, Action::Scrape as u32)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn scrape_request_is_sent_over_udp() {
+        let fake = FakeTracker::start(1, None).await;
+        let request = ScrapeRequest {
+            scrape_url: fake.url.clone(),
+            info_hashes: vec![[1; 20]],
+        };
+
+        // through the entry point, with the real timeouts
+        let response = request.send().await.unwrap();
+
+        let stats = &response.files[&ByteBuf::from(vec![1; 20])];
+        assert_eq!(
+            (stats.complete, stats.downloaded, stats.incomplete),
+            (5, 6, 7)
+        );
+        assert_eq!(
+            fake.received(),
+            vec![
+                (PROTOCOL_ID, Action::Connect as u32),
+                (PROTOCOL_ID, Action::Connect as u32),
+                (1, Action::Scrape as u32)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_id_is_reused_until_it_expires() {
+        let fake = FakeTracker::start(0, None).await;
+        let tracker = fake.connect().await;
+        let request = announce_request(&fake.url);
+
+        tracker.announce(&request).await.unwrap();
+        tracker.announce(&request).await.unwrap();
+        connection_ids()
+            .lock()
+            .unwrap()
+            .get_mut(&tracker.addr)
+            .unwrap()
+            .1 = Instant::now() - CONNECTION_ID_LIFETIME;
+        tracker.announce(&request).await.unwrap();
+
+        assert_eq!(
+            fake.received(),
+            vec![
+                (PROTOCOL_ID, Action::Connect as u32),
+                (1, Action::Announce as u32),
+                (1, Action::Announce as u32),
+                (PROTOCOL_ID, Action::Connect as u32),
+                (2, Action::Announce as u32),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn lost_requests_are_retransmitted() {
+        let fake = FakeTracker::start(1, None).await;
+        let tracker = fake.connect().await;
+
+        tracker
+            .announce(&announce_request(&fake.url))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            fake.received(),
+            vec![
+                (PROTOCOL_ID, Action::Connect as u32),
+                (PROTOCOL_ID, Action::Connect as u32),
+                (1, Action::Announce as u32),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_survives_the_retransmission_schedule() {
+        let fake = FakeTracker::start(2, None).await;
+
+        // through the entry point, with the real timeouts
+        let response = announce_request(&fake.url).send().await.unwrap();
+
+        assert_eq!(response.interval, Some(1800));
+        assert_eq!(
+            fake.received(),
+            vec![
+                (PROTOCOL_ID, Action::Connect as u32),
+                (PROTOCOL_ID, Action::Connect as u32),
+                (PROTOCOL_ID, Action::Connect as u32),
+                (1, Action::Announce as u32),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_retransmission() {
+        let fake = FakeTracker::start(usize::MAX, None).await;
+        let tracker = fake.connect().await;
+
+        let err = tracker
+            .announce(&announce_request(&fake.url))
+            .await
+            .unwrap_err();
+
+        assert!(err.to_string().contains("did not respond"), "{}", err);
+        assert_eq!(fake.received().len(), MAX_RETRANSMISSIONS as usize + 1);
+    }
+
+    #[tokio::test]
+    async fn error_action_fails_the_request() {
+        let fake = FakeTracker::start(0, Some("unknown torrent")).await;
+        let tracker = fake.connect().await;
+
+        let err = tracker
+            .announce(&announce_request(&fake.url))
+            .await
+            .unwrap_err();
+
+        match err.downcast_ref::<TrackerError>() {
+            Some(TrackerError::Failure(reason)) => assert_eq!(reason, "unknown torrent"),
+            _ => panic!("unexpected error {}", err),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use crate::hash::b_sha1;
+use crate::storage::file_path;
+use crate::torrent::{get_file_segments, FileLayout, TorrentFile};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PieceStatus {
+    Ok,
+    /// The data on disk does not match the piece hash.
+    Mismatch,
+    /// The data could not be read, e.g. a file is missing or too short.
+    Unreadable(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    Ok,
+    Missing,
+    /// Some of the pieces the file is part of are bad.
+    Damaged {
+        bad_pieces: usize,
+        pieces: usize,
+    },
+}
+
+pub struct VerifyReport {
+    pub pieces: Vec<PieceStatus>,
+    pub files: Vec<(FileLayout, FileStatus)>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.pieces.iter().all(|piece| *piece == PieceStatus::Ok)
+    }
+}
+
+/// Hashes every piece of the torrent found at `path` (laid out as by the
+/// download command) using all the available cores.
+pub fn verify(torrent: &TorrentFile, path: &Path) -> VerifyReport {
+    let layouts = torrent.get_file_layout();
+    let paths: Vec<PathBuf> = layouts
+        .iter()
+        .map(|layout| file_path(torrent, path, layout))
+        .collect();
+
+    let no_of_pieces = torrent.get_no_of_pieces();
+    let no_of_threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(no_of_pieces.max(1));
+
+    let mut pieces = vec![PieceStatus::Ok; no_of_pieces];
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..no_of_threads)
+            .map(|worker| {
+                let layouts = &layouts;
+                let paths = &paths;
+
+                scope.spawn(move || {
+                    let mut files: HashMap<usize, File> = HashMap::new();
+
+                    (worker..no_of_pieces)
+                        .step_by(no_of_threads)
+                        .map(|piece_index| {
+                            let status =
+                                verify_piece(torrent, layouts, paths, &mut files, piece_index);
+                            (piece_index, status)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        for worker in workers {
+            for (piece_index, status) in worker.join().unwrap() {
+                pieces[piece_index] = status;
+            }
+        }
+    });
+
+    let files = layouts
+        .into_iter()
+        .zip(paths)
+        .map(|(layout, path)| {
+            let status = file_status(torrent, &layout, &path, &pieces);
+            (layout, status)
+        })
+        .collect();
+
+    VerifyReport { pieces, files }
+}
+
+fn verify_piece(
+    torrent: &TorrentFile,
+    layouts: &[FileLayout],
+    paths: &[PathBuf],
+    files: &mut HashMap<usize, File>,
+    piece_index: usize,
+) -> PieceStatus {
+    let offset = piece_index * torrent.info.piece_length;
+    let length = torrent.get_piece_length(piece_index);
+
+    let mut data = vec![0; length];
+    for segment in get_file_segments(layouts, offset, length) {
+        let path = &paths[segment.file_index];
+        let file = match files.get_mut(&segment.file_index) {
+            Some(file) => file,
+            None => match File::open(path) {
+                Ok(file) => files.entry(segment.file_index).or_insert(file),
+                Err(err) => return PieceStatus::Unreadable(format!("{}: {}", path.display(), err)),
+            },
+        };
+
+        let read = file
+            .seek(SeekFrom::Start(segment.file_offset as u64))
+            .and_then(|_| {
+                file.read_exact(
+                    &mut data[segment.data_offset..segment.data_offset + segment.length],
+                )
+            });
+        if let Err(err) = read {
+            return PieceStatus::Unreadable(format!("{}: {}", path.display(), err));
+        }
+    }
+
+    let expected = torrent
+        .info
+        .pieces
+        .chunks_exact(20)
+        .nth(piece_index)
+        .unwrap();
+    if b_sha1(&data) == expected {
+        PieceStatus::Ok
+    } else {
+        PieceStatus::Mismatch
+    }
+}
+
+fn file_status(
+    torrent: &TorrentFile,
+    layout: &FileLayout,
+    path: &Path,
+    pieces: &[PieceStatus],
+) -> FileStatus {
+    if !path.is_file() {
+        return FileStatus::Missing;
+    }
+    if layout.length == 0 {
+        return FileStatus::Ok;
+    }
+
+    let first_piece = layout.offset / torrent.info.piece_length;
+    let last_piece = (layout.offset + layout.length - 1) / torrent.info.piece_length;
+    let bad_pieces = pieces[first_piece..=last_piece]
+        .iter()
+        .filter(|piece| **piece != PieceStatus::Ok)
+        .count();
+
+    if bad_pieces == 0 {
+        FileStatus::Ok
+    } else {
+        FileStatus::Damaged {
+            bad_pieces,
+            pieces: last_piece - first_piece + 1,
+        }
+    }
+}